@@ -0,0 +1,228 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::metadata::{AudioFile, Metadata, NumberOrToken};
+
+/// Order, in which the files are numbered by '--auto-track'.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum TrackOrder {
+    /// Order, in which the files are given (stdin first, then arguments)
+    Order,
+
+    /// Paths of the files, with numbers in them compared by value, so that
+    /// '2 - Foo' comes before '10 - Bar'
+    Natural,
+
+    /// Time of the last modification of the files
+    Mtime,
+}
+
+/// Groups of files, in each of which numbering starts from 1.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum TrackGroup {
+    /// Files in the same directory
+    #[default]
+    Directory,
+
+    /// Files with the same derived album
+    Album,
+
+    /// All the files together
+    Never,
+}
+
+/// Numbers the files 1..N in the given order, restarting from 1 in every
+/// group, and sets the total number of tracks to the size of the group.
+pub fn number_tracks(
+    entries: Vec<(&AudioFile, &mut Metadata)>,
+    order: TrackOrder,
+    group: TrackGroup,
+) {
+    let mut groups: Vec<(Option<String>, Vec<_>)> = Vec::new();
+
+    for (file, metadata) in entries {
+        let key = match group {
+            TrackGroup::Directory => file
+                .as_path()
+                .parent()
+                .map(|x| x.to_string_lossy().to_string()),
+            TrackGroup::Album => metadata.album_title.clone(),
+            TrackGroup::Never => None,
+        };
+
+        match groups.iter_mut().find(|(x, _)| *x == key) {
+            Some((_, entries)) => entries.push((file, metadata)),
+            None => groups.push((key, vec![(file, metadata)])),
+        }
+    }
+
+    for (_, mut entries) in groups {
+        match order {
+            TrackOrder::Order => {}
+            TrackOrder::Natural => {
+                entries.sort_by(|a, b| natural_cmp(&a.0.path(), &b.0.path()))
+            }
+            // Files, whose time can't be read, go first
+            TrackOrder::Mtime => entries.sort_by_cached_key(|(file, _)| {
+                fs::metadata(file.as_path()).and_then(|x| x.modified()).ok()
+            }),
+        }
+
+        let total = entries.len() as u32;
+
+        for (i, (_, metadata)) in entries.into_iter().enumerate() {
+            metadata.track_number = Some(NumberOrToken::Number(i as u32 + 1));
+            metadata.track_total = Some(total);
+        }
+    }
+}
+
+/// Sets the total number of tracks of the files, that have a track number
+/// and an album, but no total, to the number of files in the batch with the
+/// same album and disc. If any of the track numbers is greater than that,
+/// only a part of the album is given, and the total is left unset.
+pub fn fill_track_totals(entries: Vec<&mut Metadata>) {
+    let mut counts: HashMap<_, u32> = HashMap::new();
+
+    let mut last_tracks: HashMap<_, u32> = HashMap::new();
+
+    for metadata in &entries {
+        if let Some(key) = album_key(metadata) {
+            *counts.entry(key.clone()).or_default() += 1;
+
+            let track = track_number(metadata).unwrap_or_default();
+            let last = last_tracks.entry(key).or_default();
+            *last = track.max(*last);
+        }
+    }
+
+    for metadata in entries {
+        if metadata.track_total.is_some() || metadata.track_number.is_none() {
+            continue;
+        }
+
+        if let Some(key) = album_key(metadata) {
+            metadata.track_total = counts
+                .get(&key)
+                .copied()
+                .filter(|x| *x >= last_tracks[&key]);
+        }
+    }
+}
+
+fn track_number(metadata: &Metadata) -> Option<u32> {
+    metadata
+        .track_number
+        .as_ref()
+        .and_then(NumberOrToken::number)
+}
+
+fn album_key(metadata: &Metadata) -> Option<(String, Option<u32>)> {
+    let disc = metadata
+        .disc_number
+        .as_ref()
+        .and_then(NumberOrToken::number);
+
+    Some((metadata.album_title.clone()?, disc))
+}
+
+/// Compares strings case-insensitively, treating runs of digits as numbers.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a;
+    let mut b = b;
+
+    loop {
+        let (x, y) = match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (x, y),
+        };
+
+        let ordering = if x.is_ascii_digit() && y.is_ascii_digit() {
+            let (n, rest_a) = split_number(a);
+            let (m, rest_b) = split_number(b);
+            a = rest_a;
+            b = rest_b;
+
+            // Numbers can be too long for any of the integer types, so they
+            // are compared by length first
+            n.len().cmp(&m.len()).then(n.cmp(m))
+        } else {
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+
+            x.to_lowercase().cmp(y.to_lowercase())
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Splits the leading number (without leading zeros) from the rest of the
+/// string.
+fn split_number(string: &str) -> (&str, &str) {
+    let end = string
+        .find(|x: char| !x.is_ascii_digit())
+        .unwrap_or(string.len());
+    let (number, rest) = string.split_at(end);
+    let number = number.trim_start_matches('0');
+
+    (number, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fill_track_totals() {
+        let album = |album: &str, track: u32| Metadata {
+            album_title: Some(album.to_string()),
+            track_number: Some(NumberOrToken::Number(track)),
+            ..Default::default()
+        };
+
+        let mut batch = [
+            album("Foo", 1),
+            album("Foo", 2),
+            album("Bar", 1),
+            album("Baz", 1),
+            album("Baz", 3),
+        ];
+        batch[1].track_total = Some(10);
+
+        fill_track_totals(batch.iter_mut().collect());
+
+        let totals: Vec<_> = batch.iter().map(|x| x.track_total).collect();
+        assert_eq!(totals, [Some(2), Some(10), Some(1), None, None]);
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "10 - Foo.mp3",
+            "2 - Bar.mp3",
+            "01 - baz.mp3",
+            "Disc 1/Track 12.mp3",
+            "Disc 1/track 3.mp3",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+
+        assert_eq!(
+            names,
+            [
+                "01 - baz.mp3",
+                "2 - Bar.mp3",
+                "10 - Foo.mp3",
+                "Disc 1/track 3.mp3",
+                "Disc 1/Track 12.mp3",
+            ]
+        );
+
+        assert_eq!(natural_cmp("Track 002", "Track 2"), Ordering::Equal);
+    }
+}
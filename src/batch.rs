@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+
+use crate::error::Error;
+use crate::metadata::{AudioFile, Field, Metadata, NumberOrToken};
+use crate::{FilenameParser, ProcessOptions};
+
+// Track numbers above this are suspicious, unless there are more files in
+// the batch
+const MAX_TRACK: u32 = 30;
+const MIN_YEAR: u32 = 1900;
+
+/// Result of applying one of the regexes or patterns to one of the files.
+enum Outcome {
+    NoMatch,
+    Implausible(Metadata),
+    Match(Metadata),
+}
+
+impl Outcome {
+    fn metadata(&self) -> Option<&Metadata> {
+        match self {
+            Outcome::Match(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Chooses the regex or pattern for every file in the batch, preferring the
+/// ones, that fit the whole batch consistently, over the first one, that
+/// matches the filename. Each regex and pattern gets a score for the batch:
+/// every file it matches with plausible values, every field it fills in,
+/// every file with the same artist as another one and every track number,
+/// that is greater than the previous one, adds to it.
+///
+/// Files, that are matched only with implausible values or with the same
+/// score and different metadata, are reported as errors. Files, that aren't
+/// matched at all, get the whole list, so that the usual error is reported
+/// (or the file is looked up without parsed values).
+pub fn choose_parsers(
+    files: &[AudioFile],
+    options: &ProcessOptions,
+) -> Vec<anyhow::Result<Vec<FilenameParser>>> {
+    let parsers = &options.filename_parsers;
+    let max_track = MAX_TRACK.max(files.len() as u32);
+    let max_year = current_year();
+
+    let outcomes: Vec<Vec<_>> = parsers
+        .iter()
+        .map(|parser| {
+            files
+                .iter()
+                .map(|file| {
+                    let Ok((mut parsed, specified)) =
+                        file.apply_filename_parser(parser, &options.metadata)
+                    else {
+                        return Outcome::NoMatch;
+                    };
+                    parsed.update(&specified);
+
+                    if is_plausible(&parsed, max_track, max_year) {
+                        Outcome::Match(parsed)
+                    } else {
+                        Outcome::Implausible(parsed)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let scores: Vec<_> = outcomes.iter().map(|x| batch_score(x)).collect();
+
+    files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            // Sorting is stable, so the order of the parsers is used to
+            // break ties
+            let mut candidates: Vec<_> = (0..parsers.len())
+                .filter_map(|p| Some((p, outcomes[p][i].metadata()?)))
+                .collect();
+            candidates.sort_by_key(|(p, _)| std::cmp::Reverse(scores[*p]));
+
+            match candidates[..] {
+                [] => {
+                    let implausible =
+                        outcomes.iter().enumerate().find_map(|(p, x)| match &x
+                            [i]
+                        {
+                            Outcome::Implausible(x) => Some((p, x)),
+                            _ => None,
+                        });
+
+                    match implausible {
+                        Some((p, metadata)) => Err(Error::Parse(format!(
+                            "The filename '{}' is matched by {} only with \
+                            implausible values ({}), skipping it",
+                            file.filename_stem(),
+                            parsers[p],
+                            describe(metadata)
+                        ))
+                        .into()),
+                        None => Ok(parsers.clone()),
+                    }
+                }
+
+                [(p, a), (q, b), ..]
+                    if scores[p] == scores[q] && describe(a) != describe(b) =>
+                {
+                    Err(Error::Parse(format!(
+                        "The filename '{}' is ambiguous, skipping it. It is \
+                        matched equally well by {} ({}) and {} ({})",
+                        file.filename_stem(),
+                        parsers[p],
+                        describe(a),
+                        parsers[q],
+                        describe(b)
+                    ))
+                    .into())
+                }
+
+                [(p, _), ..] => Ok(vec![parsers[p].clone()]),
+            }
+        })
+        .collect()
+}
+
+fn batch_score(outcomes: &[Outcome]) -> u32 {
+    let matches: Vec<_> =
+        outcomes.iter().filter_map(Outcome::metadata).collect();
+
+    let mut artists: HashMap<&str, u32> = HashMap::new();
+
+    for artist in matches.iter().filter_map(|x| x.artist.as_deref()) {
+        *artists.entry(artist).or_default() += 1;
+    }
+
+    let coverage = matches.len() as u32;
+    let fields: u32 = matches.iter().map(|x| filled_fields(x)).sum();
+    let same_artist: u32 = artists.values().filter(|x| **x > 1).sum();
+    let increasing_tracks = matches
+        .windows(2)
+        .filter(|x| match (track(x[0]), track(x[1])) {
+            (Some(a), Some(b)) => a < b,
+            _ => false,
+        })
+        .count() as u32;
+
+    coverage + fields + same_artist + increasing_tracks
+}
+
+fn is_plausible(metadata: &Metadata, max_track: u32, max_year: u32) -> bool {
+    let bad_track = track(metadata).filter(|x| *x > max_track);
+    let bad_year =
+        number(&metadata.year).filter(|x| !(MIN_YEAR..=max_year).contains(x));
+
+    bad_track.is_none() && bad_year.is_none()
+}
+
+fn filled_fields(metadata: &Metadata) -> u32 {
+    Field::value_variants()
+        .iter()
+        .filter(|x| metadata.is_set(**x))
+        .count() as u32
+}
+
+fn track(metadata: &Metadata) -> Option<u32> {
+    number(&metadata.track_number)
+}
+
+fn number(x: &Option<NumberOrToken>) -> Option<u32> {
+    x.as_ref().and_then(NumberOrToken::number)
+}
+
+fn describe(metadata: &Metadata) -> String {
+    Field::value_variants()
+        .iter()
+        .filter_map(|x| Some(format!("{}: '{}'", x.name(), metadata.get(*x)?)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn current_year() -> u32 {
+    const SECONDS_PER_YEAR: u64 = 31_556_952;
+
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or_default();
+
+    1970 + (seconds / SECONDS_PER_YEAR) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(artist: &str, track: Option<u32>) -> Metadata {
+        Metadata {
+            artist: Some(artist.to_string()),
+            title: Some("Title".to_string()),
+            track_number: track.map(NumberOrToken::Number),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_batch_score() {
+        // '{d} {a} - {t}' applied to '01 Foo - Bar' and '02 Foo - Baz'
+        let tracks = [
+            Outcome::Match(metadata("Foo", Some(1))),
+            Outcome::Match(metadata("Foo", Some(2))),
+        ];
+        // '{a} - {t}' applied to the same files
+        let no_tracks = [
+            Outcome::Match(metadata("01 Foo", None)),
+            Outcome::Match(metadata("02 Foo", None)),
+        ];
+
+        assert!(batch_score(&tracks) > batch_score(&no_tracks));
+    }
+
+    #[test]
+    fn test_is_plausible() {
+        assert!(is_plausible(&metadata("Foo", Some(12)), 30, 2023));
+        assert!(!is_plausible(&metadata("Cent", Some(50)), 30, 2023));
+        assert!(is_plausible(&metadata("Cent", Some(50)), 60, 2023));
+
+        let mut future = metadata("Foo", None);
+        future.year = Some(NumberOrToken::Number(2077));
+        assert!(!is_plausible(&future, 30, 2023));
+    }
+}
@@ -0,0 +1,300 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::metadata::{Field, Metadata};
+
+// Rules, that are always available. Each of them is (set, description,
+// pattern, replacement) and is applied to the title only
+const BUILTIN_RULES: [(&str, &str, &str, &str); 6] = [
+    (
+        "youtube",
+        "Remove '(Official Music Video)', '[Official Audio]' and the like",
+        r"(?i)\s*[(\[](official\s+)?(music\s+|lyrics?\s+)?(video|audio|visualizer)[)\]]",
+        "",
+    ),
+    (
+        "youtube",
+        "Remove '(Lyrics)' and '(With Lyrics)'",
+        r"(?i)\s*[(\[](with\s+)?lyrics[)\]]",
+        "",
+    ),
+    (
+        "youtube",
+        "Remove video quality, like '[HD]' or '(4K)'",
+        r"(?i)\s*[(\[](hd|hq|4k|1080p|720p)[)\]]",
+        "",
+    ),
+    (
+        "youtube",
+        "Remove YouTube video ID, like '[dQw4w9WgXcQ]'",
+        r"\s*\[[A-Za-z0-9_-]{11}\]",
+        "",
+    ),
+    (
+        "whitespace",
+        "Replace runs of whitespace with a single space",
+        r"\s{2,}",
+        " ",
+    ),
+    (
+        "whitespace",
+        "Remove whitespace at the ends",
+        r"^\s+|\s+$",
+        "",
+    ),
+];
+
+/// Regex and its replacement, that are applied to the derived values of the
+/// fields before they are written.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    set: String,
+    description: String,
+    regex: Regex,
+    replacement: String,
+    fields: Vec<Field>,
+    is_builtin: bool,
+}
+
+/// Rule, as it is written in the rules file:
+///
+/// ```toml
+/// [[rule]]
+/// set = "youtube"
+/// description = "Remove '(Remastered)'"
+/// pattern = '\s*\(Remastered\)'
+/// replacement = ""
+/// fields = ["title", "album"]
+/// ```
+///
+/// Only `set` and `pattern` are required. Rules are removals, that are
+/// applied to the title, by default.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleDefinition {
+    set: String,
+    #[serde(default)]
+    description: String,
+    pattern: String,
+    #[serde(default)]
+    replacement: String,
+    #[serde(default = "default_fields")]
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleDefinition>,
+}
+
+fn default_fields() -> Vec<Field> {
+    vec![Field::Title]
+}
+
+impl Rule {
+    fn new(
+        definition: RuleDefinition,
+        is_builtin: bool,
+    ) -> anyhow::Result<Self> {
+        let regex = Regex::new(&definition.pattern).context(format!(
+            "Invalid pattern of the cleanup rule '{}'",
+            definition.pattern
+        ))?;
+
+        if let Some(field) = definition.fields.iter().find(|x| !x.is_text()) {
+            bail!(
+                "Cleanup rule '{}' can't be applied to the field '{}', \
+                only text fields can be cleaned",
+                definition.pattern,
+                field.name()
+            );
+        }
+
+        Ok(Self {
+            set: definition.set,
+            description: definition.description,
+            regex,
+            replacement: definition.replacement,
+            fields: definition.fields,
+            is_builtin,
+        })
+    }
+
+    fn apply(&self, metadata: &mut Metadata) {
+        for field in &self.fields {
+            let Some(value) = metadata.text_mut(*field) else {
+                continue;
+            };
+
+            let Some(old) = value.as_deref() else {
+                continue;
+            };
+
+            let new =
+                self.regex.replace_all(old, &self.replacement).into_owned();
+
+            // Field, that consisted of junk only, is left empty
+            *value = (!new.is_empty()).then_some(new);
+        }
+    }
+}
+
+fn builtin_rules() -> Vec<Rule> {
+    BUILTIN_RULES
+        .iter()
+        .map(|(set, description, pattern, replacement)| {
+            let definition = RuleDefinition {
+                set: set.to_string(),
+                description: description.to_string(),
+                pattern: pattern.to_string(),
+                replacement: replacement.to_string(),
+                fields: default_fields(),
+            };
+
+            Rule::new(definition, true).unwrap()
+        })
+        .collect()
+}
+
+/// Path of the rules file, that is used, if no other is given:
+/// `$XDG_CONFIG_HOME/fme/rules.toml` or `~/.config/fme/rules.toml`.
+fn default_rules_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            Some(PathBuf::from(env::var_os("HOME")?).join(".config"))
+        })?;
+
+    Some(config_dir.join("fme").join("rules.toml"))
+}
+
+fn read_rules_file(path: &Path) -> anyhow::Result<Vec<Rule>> {
+    let context = format!(
+        "Failed to read cleanup rules from the file '{}'",
+        path.to_string_lossy()
+    );
+
+    let contents = fs::read_to_string(path).context(context.clone())?;
+    let file: RulesFile = toml::from_str(&contents).context(context.clone())?;
+
+    file.rule
+        .into_iter()
+        .map(|x| Rule::new(x, false))
+        .collect::<anyhow::Result<_>>()
+        .context(context)
+}
+
+/// Returns built-in rules and the rules from the given file (or from the
+/// default one, if it exists).
+pub fn all_rules(rules_file: Option<&Path>) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = builtin_rules();
+
+    match rules_file {
+        Some(path) => rules.extend(read_rules_file(path)?),
+        None => {
+            if let Some(path) = default_rules_path().filter(|x| x.exists()) {
+                rules.extend(read_rules_file(&path)?);
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+/// Returns the rules of the given sets in the order, in which the sets are
+/// given.
+pub fn select_rules(
+    rules: &[Rule],
+    sets: &[String],
+) -> anyhow::Result<Vec<Rule>> {
+    let mut selected = Vec::new();
+
+    for set in sets {
+        let len = selected.len();
+        selected.extend(rules.iter().filter(|x| &x.set == set).cloned());
+
+        if selected.len() == len {
+            let mut available: Vec<_> =
+                rules.iter().map(|x| x.set.as_str()).collect();
+            available.sort();
+            available.dedup();
+
+            bail!(
+                "Unknown cleanup rule set '{set}', available sets are: {}",
+                available.join(", ")
+            );
+        }
+    }
+
+    Ok(selected)
+}
+
+pub fn clean_metadata(rules: &[Rule], metadata: &mut Metadata) {
+    for rule in rules {
+        rule.apply(metadata);
+    }
+}
+
+/// Lists the rules grouped by their sets, as they are shown to the user.
+pub fn format_rules(rules: &[Rule]) -> String {
+    let mut lines = Vec::new();
+    let mut last_set = None;
+
+    for rule in rules {
+        if last_set != Some(&rule.set) {
+            let origin = if rule.is_builtin { "built-in" } else { "user" };
+
+            lines.push(format!("{} ({origin})", rule.set));
+            last_set = Some(&rule.set);
+        }
+
+        let fields: Vec<_> = rule.fields.iter().map(|x| x.name()).collect();
+
+        if !rule.description.is_empty() {
+            lines.push(format!("  {}", rule.description));
+        }
+
+        lines.push(format!(
+            "    {} -> '{}' [{}]",
+            rule.regex,
+            rule.replacement,
+            fields.join(", ")
+        ));
+    }
+
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_youtube_rules() {
+        let rules =
+            select_rules(&builtin_rules(), &["youtube".to_string()]).unwrap();
+
+        for title in [
+            "Never Gonna Give You Up (Official Music Video)",
+            "Never Gonna Give You Up [Official Audio] [HD]",
+            "Never Gonna Give You Up (Lyrics) [dQw4w9WgXcQ]",
+        ] {
+            let mut metadata = Metadata {
+                title: Some(title.to_string()),
+                ..Default::default()
+            };
+
+            clean_metadata(&rules, &mut metadata);
+            assert_eq!(metadata.title.unwrap(), "Never Gonna Give You Up");
+        }
+
+        assert!(select_rules(&rules, &["foo".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Errors, that make processing of a single file fail. Each kind of them has
+/// its own exit code, so that scripts can tell them apart.
+#[derive(Debug)]
+pub enum Error {
+    /// File or album cover can't be read or written
+    Io { path: PathBuf, source: io::Error },
+
+    /// Format of the file or album cover isn't supported
+    UnsupportedFormat(String),
+
+    /// Filename or input can't be parsed into metadata
+    Parse(String),
+
+    /// Tags of the file can't be read
+    TagRead {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+
+    /// Tags can't be written to the file
+    TagWrite {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+}
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.as_ref().to_owned(),
+            source,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } => 3,
+            Self::UnsupportedFormat(_) => 4,
+            Self::Parse(_) => 5,
+            Self::TagRead { .. } => 6,
+            Self::TagWrite { .. } => 7,
+        }
+    }
+}
+
+// Sources aren't included in the messages, because they are printed as a
+// part of the error chain
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io { path, .. } => {
+                write!(f, "Failed to access '{}'", path.to_string_lossy())
+            }
+            Self::UnsupportedFormat(message) | Self::Parse(message) => {
+                write!(f, "{message}")
+            }
+            Self::TagRead { path, .. } => write!(
+                f,
+                "Failed to read metadata tags from the file '{}'",
+                path.to_string_lossy()
+            ),
+            Self::TagWrite { path, .. } => write!(
+                f,
+                "Failed to write metadata tags in the file '{}'",
+                path.to_string_lossy()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::TagRead { source, .. } | Self::TagWrite { source, .. } => {
+                Some(source.as_ref())
+            }
+            Self::UnsupportedFormat(_) | Self::Parse(_) => None,
+        }
+    }
+}
+
+/// Exit code for errors, that don't belong to any of the kinds above, e.g.
+/// failed requests to MusicBrainz.
+pub const OTHER_EXIT_CODE: i32 = 1;
+
+/// Returns the exit code for the error, looking for the first known error in
+/// its chain.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            return e.exit_code();
+        }
+
+        if cause.is::<io::Error>() {
+            return 3;
+        }
+    }
+
+    OTHER_EXIT_CODE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_exit_code() {
+        let parse = anyhow::Error::from(Error::Parse("Foo".to_string()));
+        assert_eq!(exit_code(&parse), 5);
+
+        // Known errors are found under the context too
+        let write: anyhow::Result<()> = Err(Error::TagWrite {
+            path: PathBuf::from("foo.mp3"),
+            source: anyhow::anyhow!("Bar"),
+        }
+        .into());
+        let write = write.context("Baz").unwrap_err();
+        assert_eq!(exit_code(&write), 7);
+
+        let io = io::Error::new(io::ErrorKind::NotFound, "Foo");
+        assert_eq!(exit_code(&anyhow::Error::from(io)), 3);
+        assert_eq!(exit_code(&anyhow::anyhow!("Foo")), 1);
+    }
+}
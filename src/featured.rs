@@ -0,0 +1,125 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::metadata::Metadata;
+
+// Words, that introduce featured artists. 'with' and 'x' are too common in
+// titles, so there they are recognized only in brackets
+const MARKERS: &str = r"feat\.?|ft\.?|featuring|with|x";
+const TITLE_MARKERS: &str = r"feat\.?|ft\.?|featuring";
+
+static ARTIST: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"(?i)^(.+?)\s+(?:{MARKERS})\s+(.+)$")).unwrap()
+});
+
+static TITLE_IN_BRACKETS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"(?i)\s*[(\[](?:{MARKERS})\s+([^)\]]+)[)\]]")).unwrap()
+});
+
+static TITLE_AT_END: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"(?i)\s+(?:{TITLE_MARKERS})\s+(.+)$")).unwrap()
+});
+
+static SEPARATOR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\s*,\s*|\s+(?:&|and|x)\s+").unwrap());
+
+/// Moves featured artists, like in 'Foo feat. Bar' or 'Title (ft. Bar &
+/// Baz)', from the artist and the title to the list of featured artists.
+/// If any of them are found, the main artist is put to the album artist,
+/// unless it is already set.
+pub fn split_featured_artists(metadata: &mut Metadata) {
+    let mut featured = Vec::new();
+
+    if let Some((artist, guests)) =
+        metadata.artist.as_deref().and_then(split_artist)
+    {
+        metadata.artist = Some(artist);
+        featured.extend(guests);
+    }
+
+    if let Some((title, guests)) =
+        metadata.title.as_deref().and_then(split_title)
+    {
+        metadata.title = Some(title);
+        featured.extend(guests);
+    }
+
+    if featured.is_empty() {
+        return;
+    }
+
+    for guest in featured {
+        let is_known = metadata.artist.as_ref() == Some(&guest)
+            || metadata.featured_artists.contains(&guest);
+
+        if !is_known {
+            metadata.featured_artists.push(guest);
+        }
+    }
+
+    if metadata.album_artist.is_none() {
+        metadata.album_artist = metadata.artist.clone();
+    }
+}
+
+fn split_artist(artist: &str) -> Option<(String, Vec<String>)> {
+    let captures = ARTIST.captures(artist)?;
+
+    Some((captures[1].to_string(), split_names(&captures[2])))
+}
+
+fn split_title(title: &str) -> Option<(String, Vec<String>)> {
+    for regex in [&*TITLE_IN_BRACKETS, &*TITLE_AT_END] {
+        if let Some(captures) = regex.captures(title) {
+            let guests = split_names(&captures[1]);
+            let title = regex.replace(title, "").trim().to_string();
+
+            return Some((title, guests));
+        }
+    }
+
+    None
+}
+
+fn split_names(names: &str) -> Vec<String> {
+    SEPARATOR
+        .split(names)
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_featured_artists() {
+        let mut metadata = Metadata {
+            artist: Some("Foo ft. Quuz".to_string()),
+            title: Some("Bar (feat. Baz & Qux)".to_string()),
+            ..Default::default()
+        };
+
+        split_featured_artists(&mut metadata);
+
+        assert_eq!(metadata.artist.as_deref(), Some("Foo"));
+        assert_eq!(metadata.album_artist.as_deref(), Some("Foo"));
+        assert_eq!(metadata.title.as_deref(), Some("Bar"));
+        assert_eq!(metadata.featured_artists, ["Quuz", "Baz", "Qux"]);
+
+        let mut metadata = Metadata {
+            artist: Some("Foo".to_string()),
+            title: Some("Dancing with Myself".to_string()),
+            ..Default::default()
+        };
+
+        split_featured_artists(&mut metadata);
+
+        assert_eq!(metadata.title.as_deref(), Some("Dancing with Myself"));
+        assert!(metadata.featured_artists.is_empty());
+        assert_eq!(metadata.album_artist, None);
+    }
+}
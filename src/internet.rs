@@ -0,0 +1,448 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::metadata::{Metadata, NumberOrToken};
+
+pub const DEFAULT_MUSICBRAINZ_URL: &str = "https://musicbrainz.org";
+
+const USER_AGENT: &str = concat!(
+    "fme/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/andreykaere/fme )"
+);
+
+// MusicBrainz asks clients to not make more than one request per second
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+const SEARCH_LIMIT: usize = 10;
+
+/// Client for the MusicBrainz web service. The base URL is configurable, so
+/// that it can be pointed to a mirror or to a local mock server.
+#[derive(Debug)]
+pub struct MusicBrainz {
+    base_url: String,
+    agent: ureq::Agent,
+    last_request: Cell<Option<Instant>>,
+}
+
+/// Result of the lookup: either one recording that fits the query best, or
+/// a list of candidates ranked from the best to the worst, when it's not
+/// clear which of them is the right one.
+#[derive(Debug, Clone)]
+pub enum Lookup {
+    Found(Box<Metadata>),
+    Ambiguous(Vec<Candidate>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub score: u32,
+    pub recording_id: String,
+    pub metadata: Metadata,
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metadata = &self.metadata;
+
+        write!(
+            f,
+            "[{}] {} - {}",
+            self.score,
+            metadata.artist.as_deref().unwrap_or("?"),
+            metadata.title.as_deref().unwrap_or("?"),
+        )?;
+
+        if let Some(album_title) = &metadata.album_title {
+            write!(f, " ({album_title}")?;
+
+            if let Some(NumberOrToken::Number(year)) = &metadata.year {
+                write!(f, ", {year}")?;
+            }
+
+            write!(f, ")")?;
+        }
+
+        write!(f, " <{}>", self.recording_id)
+    }
+}
+
+impl MusicBrainz {
+    pub fn new(base_url: &str) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(30))
+            .build();
+
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent,
+            last_request: Cell::new(None),
+        }
+    }
+
+    /// Searches for the recording, described by given (possibly incomplete)
+    /// metadata. At least the title has to be known.
+    pub fn lookup(&self, query: &Metadata) -> anyhow::Result<Lookup> {
+        let search = self.search_recordings(&build_query(query)?)?;
+
+        rank_candidates(search, query)
+    }
+
+    fn search_recordings(
+        &self,
+        query: &str,
+    ) -> anyhow::Result<RecordingSearch> {
+        self.wait_for_rate_limit();
+
+        let url = format!("{}/ws/2/recording", self.base_url);
+        let response = self
+            .agent
+            .get(&url)
+            .query("query", query)
+            .query("fmt", "json")
+            .query("limit", &SEARCH_LIMIT.to_string())
+            .call()
+            .context(format!("Failed to send request to '{url}'"))?;
+
+        response
+            .into_json()
+            .context("Failed to parse response from MusicBrainz")
+    }
+
+    fn wait_for_rate_limit(&self) {
+        if let Some(last_request) = self.last_request.get() {
+            let elapsed = last_request.elapsed();
+
+            if elapsed < REQUEST_INTERVAL {
+                thread::sleep(REQUEST_INTERVAL - elapsed);
+            }
+        }
+
+        self.last_request.set(Some(Instant::now()));
+    }
+}
+
+fn build_query(metadata: &Metadata) -> anyhow::Result<String> {
+    let Some(title) = &metadata.title else {
+        bail!("Can't search for a recording without knowing its title");
+    };
+
+    let mut query = format!("recording:{}", quote(title));
+
+    if let Some(artist) = &metadata.artist {
+        query.push_str(&format!(" AND artist:{}", quote(artist)));
+    }
+
+    if let Some(album_title) = &metadata.album_title {
+        query.push_str(&format!(" AND release:{}", quote(album_title)));
+    }
+
+    Ok(query)
+}
+
+// Puts the value in quotes, so that it is treated as a phrase by the Lucene
+// query parser, that is used by MusicBrainz
+fn quote(value: &str) -> String {
+    let escaped = value.replace('\\', r"\\").replace('"', "\\\"");
+
+    format!("\"{escaped}\"")
+}
+
+fn rank_candidates(
+    search: RecordingSearch,
+    query: &Metadata,
+) -> anyhow::Result<Lookup> {
+    let mut candidates: Vec<_> = search
+        .recordings
+        .iter()
+        .map(|recording| recording.to_candidate(query))
+        .collect();
+
+    // Sort is stable, so for equal scores the order of MusicBrainz is kept
+    candidates.sort_by_key(|x| Reverse(x.score));
+
+    let Some(best) = candidates.first() else {
+        bail!("No recordings were found in MusicBrainz");
+    };
+
+    let is_ambiguous = candidates
+        .iter()
+        .skip(1)
+        .take_while(|x| x.score == best.score)
+        .any(|x| !same_song(&x.metadata, &best.metadata));
+
+    if is_ambiguous {
+        return Ok(Lookup::Ambiguous(candidates));
+    }
+
+    Ok(Lookup::Found(Box::new(best.metadata.clone())))
+}
+
+fn same_song(a: &Metadata, b: &Metadata) -> bool {
+    let normalize = |x: &Option<String>| x.as_deref().map(str::to_lowercase);
+
+    normalize(&a.title) == normalize(&b.title)
+        && normalize(&a.artist) == normalize(&b.artist)
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordingSearch {
+    #[serde(default)]
+    recordings: Vec<Recording>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Recording {
+    id: String,
+    #[serde(default)]
+    score: u32,
+    title: String,
+    #[serde(rename = "artist-credit", default)]
+    artist_credit: Vec<ArtistCredit>,
+    #[serde(default)]
+    releases: Vec<Release>,
+}
+
+impl Recording {
+    fn artist(&self) -> Option<String> {
+        if self.artist_credit.is_empty() {
+            return None;
+        }
+
+        let artist = self
+            .artist_credit
+            .iter()
+            .map(|x| format!("{}{}", x.name, x.joinphrase))
+            .collect();
+
+        Some(artist)
+    }
+
+    fn to_candidate(&self, query: &Metadata) -> Candidate {
+        let mut metadata = Metadata {
+            title: Some(self.title.clone()),
+            artist: self.artist(),
+            ..Default::default()
+        };
+
+        let release = self
+            .releases
+            .iter()
+            .max_by_key(|release| release.rank(query));
+
+        if let Some(release) = release {
+            metadata.album_title = Some(release.title.clone());
+            metadata.year = release.year().map(NumberOrToken::Number);
+            metadata.track_number =
+                release.track_number().map(NumberOrToken::Number);
+        }
+
+        Candidate {
+            score: self.score,
+            recording_id: self.id.clone(),
+            metadata,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ArtistCredit {
+    name: String,
+    #[serde(default)]
+    joinphrase: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    title: String,
+    date: Option<String>,
+    status: Option<String>,
+    #[serde(rename = "release-group")]
+    release_group: Option<ReleaseGroup>,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+impl Release {
+    fn year(&self) -> Option<u32> {
+        self.date.as_deref()?.get(..4)?.parse().ok()
+    }
+
+    fn track_number(&self) -> Option<u32> {
+        let track = self.media.first()?.track.first()?;
+
+        track.number.parse().ok()
+    }
+
+    // We prefer releases with the same title as requested, then official
+    // albums over compilations and bootlegs, and the earliest ones at last
+    fn rank(
+        &self,
+        query: &Metadata,
+    ) -> (bool, bool, bool, Option<Reverse<&str>>) {
+        let same_title = query
+            .album_title
+            .as_deref()
+            .is_some_and(|x| x.to_lowercase() == self.title.to_lowercase());
+        let is_official = self.status.as_deref() == Some("Official");
+        let is_album = self
+            .release_group
+            .as_ref()
+            .and_then(|x| x.primary_type.as_deref())
+            == Some("Album");
+        let date = self.date.as_deref().map(Reverse);
+
+        (same_title, is_official, is_album, date)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseGroup {
+    #[serde(rename = "primary-type")]
+    primary_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Medium {
+    #[serde(default)]
+    track: Vec<Track>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Track {
+    number: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    const RESPONSE: &str = r#"{
+        "recordings": [
+            {
+                "id": "rec-1",
+                "score": 100,
+                "title": "In Da Club",
+                "artist-credit": [{ "name": "50 Cent", "joinphrase": "" }],
+                "releases": [
+                    {
+                        "title": "Now That's What I Call Music! 14",
+                        "date": "2003-07-15",
+                        "status": "Official",
+                        "release-group": { "primary-type": "Album" },
+                        "media": [{ "track": [{ "number": "2" }] }]
+                    },
+                    {
+                        "title": "Get Rich or Die Tryin'",
+                        "date": "2003-02-06",
+                        "status": "Official",
+                        "release-group": { "primary-type": "Album" },
+                        "media": [{ "track": [{ "number": "4" }] }]
+                    }
+                ]
+            },
+            {
+                "id": "rec-2",
+                "score": 87,
+                "title": "In da Club (remix)",
+                "artist-credit": [{ "name": "50 Cent", "joinphrase": "" }]
+            }
+        ]
+    }"#;
+
+    fn query(title: &str, artist: &str) -> Metadata {
+        Metadata {
+            title: Some(title.to_string()),
+            artist: Some(artist.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_query() {
+        assert_eq!(
+            build_query(&query("Say \"Hi\"", "Foo")).unwrap(),
+            r#"recording:"Say \"Hi\"" AND artist:"Foo""#
+        );
+
+        assert!(build_query(&Metadata::default()).is_err());
+    }
+
+    #[test]
+    fn test_rank_candidates() {
+        let search = serde_json::from_str(RESPONSE).unwrap();
+        let lookup =
+            rank_candidates(search, &query("In Da Club", "50 Cent")).unwrap();
+
+        let Lookup::Found(metadata) = lookup else {
+            panic!("Expected unambiguous result, got {lookup:?}");
+        };
+
+        assert_eq!(metadata.album_title.unwrap(), "Get Rich or Die Tryin'");
+        assert!(matches!(metadata.year, Some(NumberOrToken::Number(2003))));
+        assert!(matches!(
+            metadata.track_number,
+            Some(NumberOrToken::Number(4))
+        ));
+    }
+
+    #[test]
+    fn test_ambiguous_candidates() {
+        let response = RESPONSE.replace("\"score\": 87", "\"score\": 100");
+        let search = serde_json::from_str(&response).unwrap();
+        let lookup =
+            rank_candidates(search, &query("In Da Club", "50 Cent")).unwrap();
+
+        let Lookup::Ambiguous(candidates) = lookup else {
+            panic!("Expected ambiguous result, got {lookup:?}");
+        };
+
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].recording_id, "rec-1");
+    }
+
+    #[test]
+    fn test_lookup_with_custom_url() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            reader.read_line(&mut request_line).unwrap();
+
+            // Skip the rest of the request headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{RESPONSE}",
+                RESPONSE.len()
+            )
+            .unwrap();
+
+            request_line
+        });
+
+        let musicbrainz = MusicBrainz::new(&base_url);
+        let lookup = musicbrainz.lookup(&query("In Da Club", "50 Cent"));
+        let request_line = server.join().unwrap();
+
+        assert!(request_line.starts_with("GET /ws/2/recording?query="));
+        assert!(matches!(lookup, Ok(Lookup::Found(_))));
+    }
+}
@@ -0,0 +1,150 @@
+//! Library behind `fme`, the flexible metadata editor. It can be used to
+//! derive metadata of audio files from their filenames (or look it up in
+//! MusicBrainz), preview the changes and write them to the tags of the
+//! files.
+//!
+//! The usual flow is:
+//! 1. Open the file with [`AudioFile::new`].
+//! 2. Derive metadata with [`AudioFile::derive_metadata`], using the
+//!    patterns and regexes from [`ProcessOptions`]. Values, that are given
+//!    in [`ProcessOptions::metadata`], take precedence over the derived
+//!    ones. Any other metadata can be merged with [`Metadata::update`].
+//! 3. Check what would change with [`AudioFile::preview`].
+//! 4. Write the metadata with [`AudioFile::write_metadata`].
+//!
+//! ```no_run
+//! use fme::{AudioFile, FilenameParser, Metadata, ProcessOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let file = AudioFile::new("Artist - Title.mp3")?;
+//! let options = ProcessOptions {
+//!     filename_parsers: vec![FilenameParser::Pattern("{a} - {t}".parse()?)],
+//!     ..Default::default()
+//! };
+//!
+//! let (mut metadata, source) =
+//!     file.derive_metadata(&options, &options.filename_parsers)?;
+//! println!("Derived from {source}");
+//! metadata.update(&Metadata {
+//!     album_title: Some("Album".to_string()),
+//!     ..Default::default()
+//! });
+//!
+//! for change in file.preview(&metadata, &options.policy, &options.clear)? {
+//!     println!("{}: {:?}", change.name, change.change);
+//! }
+//!
+//! file.write_metadata(&metadata, &options.policy, &options.clear)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+
+pub mod autotrack;
+pub mod batch;
+pub mod clean;
+pub mod error;
+pub mod internet;
+pub mod metadata;
+pub mod organize;
+pub mod parse;
+pub mod rename;
+pub mod show;
+
+mod featured;
+mod transform;
+
+pub use error::Error;
+pub use metadata::{AudioFile, ClearFields, Field, Metadata, WritePolicy};
+pub use parse::ParsePattern;
+pub use show::{Change, FieldChange};
+
+use clean::Rule;
+use internet::{MusicBrainz, DEFAULT_MUSICBRAINZ_URL};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum Mode {
+    /// Derive metadata from the filename
+    #[default]
+    #[value(alias = "f", name = "filename")]
+    FromFilename,
+
+    /// Look up metadata in MusicBrainz, using the title and the artist
+    /// derived from the filename or taken from the existing tags
+    #[value(alias = "i", name = "internet")]
+    FromInternet,
+}
+
+/// Regex or pattern, that is used to derive metadata from the filename.
+#[derive(Debug, Clone)]
+pub enum FilenameParser {
+    Regex(String),
+    Pattern(ParsePattern),
+}
+
+impl FilenameParser {
+    /// Parsers, that are used when no regexes and patterns are given.
+    pub fn default_parsers() -> Vec<Self> {
+        ParsePattern::default_patterns()
+            .into_iter()
+            .map(Self::Pattern)
+            .collect()
+    }
+}
+
+impl fmt::Display for FilenameParser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilenameParser::Regex(regex) => write!(f, "regex '{regex}'"),
+            FilenameParser::Pattern(pattern) => {
+                write!(f, "pattern '{pattern}'")
+            }
+        }
+    }
+}
+
+/// Place, where metadata of a file was derived from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Filename(FilenameParser),
+    MusicBrainz,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Filename(parser) => write!(f, "{parser}"),
+            Source::MusicBrainz => write!(f, "MusicBrainz"),
+        }
+    }
+}
+
+/// Settings of deriving and writing metadata, that are shared by all the
+/// processed files. By default, metadata is derived from the filename with
+/// the default patterns and written as it is.
+pub struct ProcessOptions {
+    pub metadata: Metadata,
+    pub mode: Mode,
+    pub filename_parsers: Vec<FilenameParser>,
+    pub musicbrainz: MusicBrainz,
+    pub policy: WritePolicy,
+    pub clear: ClearFields,
+    pub clean_rules: Vec<Rule>,
+    pub split_featured: bool,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            metadata: Metadata::default(),
+            mode: Mode::default(),
+            filename_parsers: FilenameParser::default_parsers(),
+            musicbrainz: MusicBrainz::new(DEFAULT_MUSICBRAINZ_URL),
+            policy: WritePolicy::default(),
+            clear: ClearFields::default(),
+            clean_rules: Vec::new(),
+            split_featured: false,
+        }
+    }
+}
@@ -1,30 +1,91 @@
 use atty::Stream;
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use std::io;
 use std::path::{Path, PathBuf};
 
-mod metadata;
-mod parse;
-
-use metadata::{AudioFile, Metadata};
-use parse::ParsePattern;
+use fme::autotrack::{self, TrackGroup, TrackOrder};
+use fme::internet::{MusicBrainz, DEFAULT_MUSICBRAINZ_URL};
+use fme::metadata::{
+    AudioFile, ClearFields, Field, Metadata, StripTarget, WritePolicy,
+};
+use fme::organize::{self, PathTemplate, Transfer, DEFAULT_TEMPLATE};
+use fme::parse::ParsePattern;
+use fme::rename::{self, Rename};
+use fme::show::{self, Change, FieldChange, OutputFormat, TagSummary};
+use fme::{batch, clean, error};
+use fme::{FilenameParser, Mode, ProcessOptions};
 
 // TODO: think about how it's better to deal with non-ascii case
 // around this
 
-// TODO: think about extracting existing metadata and having an option of
-// overwriting existing metadata or not. It maybe useful, when existing
-// metadata is right and the automatically derived one is wrong.
-//
-
-// TODO: drop `audiotags` in favor of `lofty`
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about)]
 pub struct Opts {
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Options of the commands, that write metadata to the files
+#[derive(clap::Args, Debug)]
+struct WriteArgs {
     #[clap(flatten)]
     metadata: Metadata,
 
+    #[clap(flatten)]
+    policy: WritePolicy,
+
+    #[clap(flatten)]
+    clear: ClearFields,
+
+    /// Number the files 1..N in the given order, instead of taking track
+    /// numbers from the filenames, and write N as the total number of
+    /// tracks. This is useful for files, that are named by the title only.
+    /// Numbering starts over in every group, see '--restart-tracks'.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ORDER",
+        conflicts_with = "track_number",
+        help = "Number tracks automatically in the given order",
+        long_help
+    )]
+    auto_track: Option<TrackOrder>,
+
+    /// Set the groups of files, that are numbered separately by
+    /// '--auto-track'.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "GROUP",
+        default_value_t,
+        requires = "auto_track",
+        help = "Start numbering from 1 in every group of files",
+        long_help
+    )]
+    restart_tracks: TrackGroup,
+
+    /// Run the whole pipeline of deriving metadata (parsing the filename,
+    /// applying regex, looking up, merging with the specified values) and
+    /// print the old and the new value of each field for every file, without
+    /// writing anything to the files. It is useful to check what the given
+    /// (or the default) patterns actually match.
+    #[arg(
+        long,
+        short = 'n',
+        help = "Print changes, that would be made, without writing them",
+        long_help
+    )]
+    dry_run: bool,
+
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ParseArgs {
+    #[command(flatten)]
+    write: WriteArgs,
+
     #[arg(
         long,
         value_enum,
@@ -33,34 +94,67 @@ pub struct Opts {
     )]
     mode: Mode,
 
+    /// Base URL of the MusicBrainz web service, that is used in the
+    /// 'internet' mode. It can be changed to use a mirror of MusicBrainz or a
+    /// local server.
+    #[arg(
+        long,
+        value_name = "URL",
+        default_value = DEFAULT_MUSICBRAINZ_URL,
+        help = "Set the base URL of the MusicBrainz web service",
+        long_help
+    )]
+    musicbrainz_url: String,
+
     /// When this option is specified, the program will try to match the given
     /// patterns (in the given order) with the filename and write extracted
     /// information to the metadata. Here by filename we mean the final
     /// component of the path taken up to extension (i.e. regular filename
-    /// with extension removed).
+    /// with extension removed). If the pattern contains '/', it is matched
+    /// against the same number of trailing components of the full path, so
+    /// that names of the parent directories can be parsed too.
     ///
     /// You can use the following correspondence when writing your pattern:
-    /// {n}    Artist <-> {a}
-    /// {n}    Title  <-> {t}
-    /// {n}    Album  <-> {m}
-    /// {n}    Year   <-> {y}
-    /// {n}    Track  <-> {d}
+    /// {n}    Artist        <-> {a}
+    /// {n}    Title         <-> {t}
+    /// {n}    Album         <-> {m}
+    /// {n}    Album artist  <-> {A}
+    /// {n}    Year          <-> {y}
+    /// {n}    Track         <-> {d}
+    /// {n}    Disc          <-> {D}
+    /// {n}    Genre         <-> {g}
+    /// {n}    Composer      <-> {c}
+    /// {n}    Comment       <-> {C}
+    /// {n}    Grouping      <-> {G}
+    /// {n}    Copyright     <-> {r}
+    /// {n}    BPM           <-> {b}
+    /// {n}    Lyrics        <-> {l}
+    /// {n}    (ignored)     <-> {_}
+    ///
+    /// Parts of the pattern in square brackets are optional, e.g. '[{d}. ]'
+    /// matches both with and without the track number. Texts separated with
+    /// '|' in parentheses are alternatives, e.g. '(-|—)' matches any of the
+    /// dashes (parentheses with tokens inside or without '|' are matched
+    /// literally). Use '\' to match any of '{}[]()|\' literally.
+    ///
+    /// Tokens can have modifiers. Width of a number, like '{d:02}', is the
+    /// exact number of its digits. Transforms, like '{t|trim|title}', are
+    /// applied to the matched value in the given order:
+    /// {n}    trim             remove whitespace at the ends
+    /// {n}    collapse         replace runs of whitespace with one space
+    /// {n}    lower, upper     change the case of all the letters
+    /// {n}    title            capitalize every word
+    /// {n}    replace(a,b)     replace 'a' with 'b' (spaces are kept)
     ///
     /// When no parser pattern specified parser tries to apply the following
     /// default patters in the given order:
-    /// {n}   1. {d} {a} - {t}
-    /// {n}   2. {d} {a} — {t}
-    /// {n}   3. {d}. {a} - {t}
-    /// {n}   4. {d}. {a} — {t}
-    /// {n}   5. {a} - {d} {t}
-    /// {n}   6. {a} — {d} {t}
-    /// {n}   7. {a} - {d}. {t}
-    /// {n}   8. {a} — {d}. {t}
-    /// {n}   9. {a} - {t}
-    /// {n}  10. {a} — {t}
-    /// {n}  11. {d} {t}
-    /// {n}  12. {d}. {t}
-    /// {n}  13. {t}
+    /// {n}   1. {a}/{y} - {m}/{d}( -|.) {t}
+    /// {n}   2. {a}/{m} ({y})/{d}( -|.) {t}
+    /// {n}   3. {d}[.] {a} (-|—|–) {t}
+    /// {n}   4. {a} (-|—|–) {d}[.] {t}
+    /// {n}   5. {a} (-|—|–) {t}
+    /// {n}   6. {d}[.] {t}
+    /// {n}   7. {t}
     #[arg(
         long,
         short,
@@ -71,43 +165,301 @@ pub struct Opts {
 
     /// When this option is specified, and provided regex pattern matches the
     /// filename, it puts the captured group into tokens: i-th group is put in
-    /// token `${i}`. Numeration of the groups starts from 1. You can use then
-    /// these tokens in options like '--artist' or '--title'. Here by filename
-    /// we mean the final component of the path taken up to extension (i.e.
-    /// regular filename with extension removed).
+    /// token `${i}`. Numeration of the groups starts from 1. Named groups are
+    /// also put in token `${name}`. You can use then these tokens in options
+    /// like '--artist' or '--title'. Here by filename we mean the final
+    /// component of the path taken up to extension (i.e. regular filename
+    /// with extension removed).
+    ///
+    /// Named groups, that are called after the fields, are written to these
+    /// fields directly, unless the field is specified explicitly:
+    /// {n}    Artist        <-> (?P<artist>...)
+    /// {n}    Title         <-> (?P<title>...)
+    /// {n}    Album         <-> (?P<album>...)
+    /// {n}    Album artist  <-> (?P<album_artist>...)
+    /// {n}    Year          <-> (?P<year>...)
+    /// {n}    Track         <-> (?P<track>...)
+    /// {n}    Disc          <-> (?P<disc>...)
+    /// {n}    Genre         <-> (?P<genre>...)
+    /// {n}    Composer      <-> (?P<composer>...)
+    /// {n}    Comment       <-> (?P<comment>...)
+    /// {n}    Grouping      <-> (?P<grouping>...)
+    /// {n}    Copyright     <-> (?P<copyright>...)
+    /// {n}    BPM           <-> (?P<bpm>...)
+    /// {n}    Lyrics        <-> (?P<lyrics>...)
+    ///
+    /// This option can be given several times and together with '--parse':
+    /// regexes and patterns are tried in the order, in which they were given,
+    /// until one of them matches the filename. The ones, that don't have a
+    /// group for some of the tokens, are skipped.
+    ///
+    /// Tokens can have the same transforms as the tokens of '--parse', e.g.
+    /// `${1|replace(_, )|title}`.
     ///
     /// Note: in contrast to `--parse` option, with `--regex` you should
     /// escape charaters as in any regular expression.
     #[arg(
         long,
         short = 'e',
-        conflicts_with("parse"),
         help = "Try to apply regex to the filename and writes matched groups to special tokens",
         long_help
     )]
-    regex: Option<String>,
+    regex: Vec<String>,
+
+    /// Print, which of the given regexes or patterns matched the filename
+    /// (or that metadata was found in MusicBrainz) for every processed file.
+    #[arg(
+        long,
+        short,
+        help = "Print, where metadata of every file was derived from",
+        long_help
+    )]
+    verbose: bool,
+
+    /// By default, regexes and patterns are chosen for the whole batch of
+    /// files: the ones, that match most of the files with consistent
+    /// artists, increasing track numbers and plausible values, are preferred
+    /// and files, that can't be parsed unambiguously, are skipped. With this
+    /// option the first regex or pattern, that matches the filename, is used
+    /// for every file independently.
+    #[arg(
+        long,
+        help = "Use the first matching regex or pattern for every file",
+        long_help
+    )]
+    first_match: bool,
+
+    /// Clean up the derived values with the rules of the given sets before
+    /// writing them. Built-in sets are 'youtube', which removes suffixes
+    /// like '(Official Music Video)', '[HD]' or a video ID from the title,
+    /// and 'whitespace'. More rules (and sets) can be defined in the rules
+    /// file. Rules of the sets are applied in the given order.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "SETS",
+        help = "Clean up the derived values with the given rule sets",
+        long_help
+    )]
+    clean: Vec<String>,
+
+    /// TOML file with cleanup rules. Each rule is a '[[rule]]' table with
+    /// the name of the 'set' it belongs to, the regex 'pattern' and,
+    /// optionally, the 'replacement' (empty by default), the 'description'
+    /// and the 'fields' it is applied to (only the title by default). By
+    /// default, rules are read from '$XDG_CONFIG_HOME/fme/rules.toml' (or
+    /// '~/.config/fme/rules.toml'), if it exists.
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Read cleanup rules from the given file",
+        long_help
+    )]
+    rules: Option<PathBuf>,
+
+    /// Recognize featured artists, like in 'Foo feat. Bar' or 'Title (ft.
+    /// Bar & Baz)', in the derived artist and title (the words are 'feat.',
+    /// 'ft.', 'featuring', 'with' and 'x'). They are removed from the title
+    /// and written as separate values of the artist field after the main
+    /// artist. If any are found, the main artist is also written to the
+    /// album artist field, unless it is set.
+    #[arg(
+        long,
+        help = "Move featured artists from the title to the artist field",
+        long_help
+    )]
+    split_featured: bool,
+
+    /// Print the rules of the sets given with '--clean' or, if there are
+    /// none, all available rules, and exit.
+    #[arg(long, help = "Print cleanup rules and exit", long_help)]
+    list_rules: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Write the given values to the tags of the files
+    Set(WriteArgs),
+
+    /// Derive metadata from the filenames (or look it up in MusicBrainz) and
+    /// write it to the files
+    Parse(ParseArgs),
+
+    /// Print metadata, that is already written to the files
+    Show(ShowArgs),
+
+    /// Rename files using their tags
+    Rename(RenameArgs),
+
+    /// Move files into a directory tree, built from their tags
+    Organize(OrganizeArgs),
+
+    /// Remove tags or some of their frames from the files
+    Strip(StripArgs),
+
+    /// Embed the album cover into the files or remove it
+    Cover(CoverArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ShowArgs {
+    /// Set the output format
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RenameArgs {
+    /// Pattern, that is used as a template for the new filename (without
+    /// extension, which is kept as it is). Tokens are the same as for the
+    /// '--parse' option and are replaced with the values of the
+    /// corresponding tags. Track numbers are padded with zeros. Characters,
+    /// that are not allowed in filenames, are replaced with underscores.
+    #[arg(long, short)]
+    pattern: ParsePattern,
+
+    /// Only print how the files would be renamed
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct OrganizeArgs {
+    /// Root directory of the library
+    #[arg(long, short = 'o', value_name = "DIR")]
+    root: PathBuf,
+
+    /// Template of the path of each file in the library, relative to its
+    /// root. Directories are separated with '/' and are created if needed.
+    ///
+    /// Each directory and the filename are patterns, that are rendered in
+    /// the same way as by 'fme rename': tokens, transforms and optional
+    /// sections are the same as for the '--parse' option. Album artist {A}
+    /// falls back to the artist, if it's not set. The template may end
+    /// with '.{ext}', which is replaced with the extension of the file.
+    #[arg(long, short = 'T', default_value = DEFAULT_TEMPLATE)]
+    template: PathTemplate,
+
+    /// Copy files instead of moving them
+    #[arg(long, conflicts_with = "hardlink")]
+    copy: bool,
+
+    /// Create hard links instead of moving files
+    #[arg(long)]
+    hardlink: bool,
+
+    /// Only print where the files would be put
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct StripArgs {
+    /// Remove only the given tags and frames. All the tags are removed, if
+    /// none are given
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "TARGETS")]
+    only: Vec<StripTarget>,
+
+    files: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CoverArgs {
+    /// Image (PNG or JPEG), that is embedded into the files
+    #[arg(
+        long,
+        short,
+        value_name = "FILE",
+        required_unless_present = "remove",
+        conflicts_with = "remove"
+    )]
+    image: Option<PathBuf>,
+
+    /// Remove the album cover from the files
+    #[arg(long)]
+    remove: bool,
+
+    /// Only print the changes, that would be made
+    #[arg(long, short = 'n')]
+    dry_run: bool,
 
     files: Vec<PathBuf>,
 }
 
-#[derive(clap::ValueEnum, Debug, Clone, Copy)]
-pub enum Mode {
-    #[value(alias = "f", name = "filename")]
-    FromFilename,
+impl OrganizeArgs {
+    fn transfer(&self) -> Transfer {
+        if self.copy {
+            Transfer::Copy
+        } else if self.hardlink {
+            Transfer::Hardlink
+        } else {
+            Transfer::Move
+        }
+    }
+}
+
+/// Collects regexes and patterns in the order, in which they were given on
+/// the command line. Default patterns are used, if there are none.
+fn filename_parsers(
+    args: &ParseArgs,
+    matches: &ArgMatches,
+) -> Vec<FilenameParser> {
+    let patterns = args.parse.iter().flatten().cloned();
+    let patterns = matches
+        .indices_of("parse")
+        .into_iter()
+        .flatten()
+        .zip(patterns.map(FilenameParser::Pattern));
+
+    let regexes = args.regex.iter().cloned();
+    let regexes = matches
+        .indices_of("regex")
+        .into_iter()
+        .flatten()
+        .zip(regexes.map(FilenameParser::Regex));
+
+    let mut parsers: Vec<_> = patterns.chain(regexes).collect();
 
-    #[value(alias = "i", name = "internet")]
-    FromInternet,
+    if parsers.is_empty() {
+        return FilenameParser::default_parsers();
+    }
+
+    parsers.sort_by_key(|(i, _)| *i);
+    parsers.into_iter().map(|(_, x)| x).collect()
 }
 
-#[derive(Debug, Clone)]
-pub enum FilenameParseMode {
-    Regex(String),
-    Parser(Vec<ParsePattern>),
+/// Errors of the files, that failed. They are printed as soon as they
+/// occur, and the rest of the files are processed.
+#[derive(Default)]
+struct Failures {
+    exit_code: i32,
+}
+
+impl Failures {
+    /// Prints the error and remembers its exit code. If the files fail with
+    /// different kinds of errors, the exit code is 1.
+    fn report(&mut self, error: &anyhow::Error) {
+        eprintln!("{error:#}");
+
+        let code = error::exit_code(error);
+        self.exit_code = match self.exit_code {
+            0 => code,
+            x if x == code => code,
+            _ => error::OTHER_EXIT_CODE,
+        };
+    }
 }
 
 fn get_all_files(
     files_from_args: &[PathBuf],
     files_from_stdin: &[PathBuf],
+    failures: &mut Failures,
 ) -> Vec<AudioFile> {
     let mut files = Vec::new();
     let files_iter = files_from_stdin.iter().chain(files_from_args);
@@ -115,49 +467,445 @@ fn get_all_files(
     for file in files_iter {
         match AudioFile::new(file) {
             Ok(audio_file) => files.push(audio_file),
-            Err(e) => eprintln!("{e}"),
+            Err(e) => failures.report(&e),
         }
     }
 
     files
 }
 
+/// Reads paths from stdin, one per line. Lines, that can't be read (e.g.
+/// because they aren't valid UTF-8), are reported and skipped.
+fn read_files_from_stdin(failures: &mut Failures) -> Vec<PathBuf> {
+    if atty::is(Stream::Stdin) {
+        return Vec::new();
+    }
+
+    let mut files = Vec::new();
+
+    for (i, line) in io::stdin().lines().enumerate() {
+        match line {
+            Ok(x) => files.push(Path::new(&x).into()),
+            Err(e) => failures.report(
+                &anyhow::Error::new(e)
+                    .context(format!("Failed to read line {} of stdin", i + 1)),
+            ),
+        }
+    }
+
+    files
+}
+
+fn get_files_or_exit(
+    files_from_args: &[PathBuf],
+    failures: &mut Failures,
+) -> Vec<AudioFile> {
+    let files_from_stdin = read_files_from_stdin(failures);
+
+    if files_from_args.is_empty() && files_from_stdin.is_empty() {
+        eprintln!("No files were provided");
+        std::process::exit(2);
+    }
+
+    let files = get_all_files(files_from_args, &files_from_stdin, failures);
+
+    // Failures of the given files are already reported
+    if files.is_empty() {
+        std::process::exit(failures.exit_code);
+    }
+
+    files
+}
+
 fn main() {
-    let args = Opts::parse();
-    let files_from_args = &args.files;
-    let metadata = &args.metadata;
-    let mode = args.mode;
-    let regex = &args.regex;
-    let parse_patterns =
-        &args.parse.unwrap_or(ParsePattern::default_patterns());
-
-    let filename_parse_mode = match regex {
-        Some(exp) => FilenameParseMode::Regex(exp.clone()),
-        None => FilenameParseMode::Parser(parse_patterns.clone()),
+    let matches = Opts::command().get_matches();
+    let args = Opts::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let mut failures = Failures::default();
+
+    match &args.command {
+        Command::Set(set_args) => set_metadata(set_args, &mut failures),
+
+        Command::Parse(parse_args) => {
+            // Order of the regexes and patterns is only known from the
+            // matches of the subcommand
+            let matches =
+                matches.subcommand_matches("parse").unwrap_or(&matches);
+            parse_metadata(parse_args, matches, &mut failures);
+        }
+
+        Command::Show(show_args) => show_tags(show_args, &mut failures),
+
+        Command::Rename(rename_args) => {
+            let files = get_files_or_exit(&rename_args.files, &mut failures);
+            let plan = rename::plan_renames(&files, &rename_args.pattern);
+
+            carry_out(plan, rename_args.dry_run, &mut failures, Rename::apply);
+        }
+
+        Command::Organize(organize_args) => {
+            let files = get_files_or_exit(&organize_args.files, &mut failures);
+            let plan = organize::plan_organize(
+                &files,
+                &organize_args.template,
+                &organize_args.root,
+            );
+            let transfer = organize_args.transfer();
+
+            carry_out(plan, organize_args.dry_run, &mut failures, |x| {
+                organize::transfer_file(x, transfer)
+            });
+        }
+
+        Command::Strip(strip_args) => strip_files(strip_args, &mut failures),
+
+        Command::Cover(cover_args) => set_cover(cover_args, &mut failures),
+    }
+
+    std::process::exit(failures.exit_code);
+}
+
+/// Prints metadata of the files. Files, that we failed to read, are
+/// reported and skipped.
+fn show_tags(args: &ShowArgs, failures: &mut Failures) {
+    let mut summaries = Vec::new();
+
+    for file in get_files_or_exit(&args.files, failures) {
+        match TagSummary::new(&file) {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => failures.report(&e),
+        }
+    }
+
+    match show::format_tags(&summaries, args.format) {
+        Ok(output) => println!("{output}"),
+        Err(e) => failures.report(&e),
+    }
+}
+
+/// Prints the plan of moving files and then carries it out, unless it is a
+/// dry run. Files, that can't be moved, are reported and skipped.
+fn carry_out(
+    plan: Vec<anyhow::Result<Rename>>,
+    dry_run: bool,
+    failures: &mut Failures,
+    apply: impl Fn(&Rename) -> anyhow::Result<()>,
+) {
+    for rename in &plan {
+        match rename {
+            Ok(x) if x.is_noop() => {
+                println!("{} (unchanged)", x.from.to_string_lossy());
+            }
+            Ok(x) => println!(
+                "{} -> {}",
+                x.from.to_string_lossy(),
+                x.to.to_string_lossy()
+            ),
+            Err(e) => failures.report(e),
+        }
+    }
+
+    if dry_run {
+        return;
+    }
+
+    for rename in plan.iter().flatten().filter(|x| !x.is_noop()) {
+        if let Err(e) = apply(rename) {
+            failures.report(&e);
+        }
+    }
+}
+
+/// Removes the tags and frames from the files and prints what was removed
+/// from each of them.
+fn strip_files(args: &StripArgs, failures: &mut Failures) {
+    for file in get_files_or_exit(&args.files, failures) {
+        match file.strip(&args.only) {
+            Ok(removed) if removed.is_empty() => {
+                println!("{}: nothing to remove", file.path());
+            }
+
+            Ok(removed) => {
+                println!("{}: removed {}", file.path(), removed.join(", "));
+            }
+
+            Err(e) => failures.report(&e),
+        }
+    }
+}
+
+/// Writes the given values to the files as they are.
+fn set_metadata(args: &WriteArgs, failures: &mut Failures) {
+    let clears = !args.clear.clear.is_empty() || args.clear.clear_all;
+
+    // Otherwise the files would be rewritten without any changes
+    if args.metadata == Metadata::default()
+        && !clears
+        && args.auto_track.is_none()
+    {
+        usage_error(
+            ErrorKind::MissingRequiredArgument,
+            "nothing to set: give the values of the fields, '--clear' or \
+            '--auto-track'",
+        );
+    }
+
+    // Tokens can be used only with regexes in 'fme parse', so here they
+    // are just invalid numbers
+    if let Err(e) = args.metadata.check_numbers() {
+        usage_error(ErrorKind::ValueValidation, e);
+    }
+
+    let mut metadata = args.metadata.clone();
+    metadata.split_totals();
+
+    let options = ProcessOptions {
+        metadata,
+        policy: args.policy.clone(),
+        clear: args.clear.clone(),
+        ..Default::default()
     };
 
-    let files_from_stdin = if atty::is(Stream::Stdin) {
-        Vec::new()
-    } else {
-        io::stdin()
-            .lines()
-            .map(|x| Path::new(&x.unwrap()).into())
+    let files = get_files_or_exit(&args.files, failures);
+    let derived = files.iter().map(|_| Ok(options.metadata.clone())).collect();
+
+    write_files(&files, derived, args, &options, failures);
+}
+
+/// Derives metadata from the filenames (or looks it up in MusicBrainz) and
+/// writes it to the files.
+fn parse_metadata(
+    args: &ParseArgs,
+    matches: &ArgMatches,
+    failures: &mut Failures,
+) {
+    let clean_rules = clean::all_rules(args.rules.as_deref())
+        .and_then(|x| {
+            // All the rules are listed, if no sets are selected
+            if args.clean.is_empty() && args.list_rules {
+                Ok(x)
+            } else {
+                clean::select_rules(&x, &args.clean)
+            }
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("{e:#}");
+            std::process::exit(2);
+        });
+
+    if args.list_rules {
+        println!("{}", clean::format_rules(&clean_rules));
+        return;
+    }
+
+    let options = ProcessOptions {
+        metadata: args.write.metadata.clone(),
+        mode: args.mode,
+        filename_parsers: filename_parsers(args, matches),
+        musicbrainz: MusicBrainz::new(&args.musicbrainz_url),
+        policy: args.write.policy.clone(),
+        clear: args.write.clear.clone(),
+        clean_rules,
+        split_featured: args.split_featured,
+    };
+
+    let files = get_files_or_exit(&args.write.files, failures);
+
+    let filename_parsers = if args.first_match {
+        files
+            .iter()
+            .map(|_| Ok(options.filename_parsers.clone()))
             .collect()
+    } else {
+        batch::choose_parsers(&files, &options)
     };
 
-    let files = get_all_files(files_from_args, &files_from_stdin);
+    let mut derived: Vec<_> = files
+        .iter()
+        .zip(filename_parsers)
+        .map(|(file, filename_parsers)| {
+            let (metadata, source) = filename_parsers
+                .and_then(|x| file.derive_metadata(&options, &x))?;
 
-    if files.is_empty() {
-        eprintln!("No files were provided");
-        std::process::exit(2);
+            if args.verbose {
+                println!("{}: derived from {source}", file.path());
+            }
+
+            Ok(metadata)
+        })
+        .collect();
+
+    // Totals are guessed only for the track numbers, that are derived from
+    // the filenames, not for the ones, that are given explicitly
+    if args.write.auto_track.is_none() {
+        autotrack::fill_track_totals(
+            derived.iter_mut().filter_map(|x| x.as_mut().ok()).collect(),
+        );
+    }
+
+    write_files(&files, derived, &args.write, &options, failures);
+}
+
+/// Embeds the album cover into the files or removes it from them.
+fn set_cover(args: &CoverArgs, failures: &mut Failures) {
+    let metadata = Metadata {
+        album_cover: args.image.clone(),
+        ..Default::default()
+    };
+    let clear = ClearFields {
+        clear: if args.remove {
+            vec![Field::Cover]
+        } else {
+            Vec::new()
+        },
+        clear_all: false,
+    };
+    let policy = WritePolicy::default();
+
+    for file in get_files_or_exit(&args.files, failures) {
+        let result = if args.dry_run {
+            print_diff(&file, &metadata, &policy, &clear)
+        } else {
+            file.write_metadata(&metadata, &policy, &clear)
+        };
+
+        if let Err(e) = result {
+            failures.report(&e);
+        }
+    }
+}
+
+/// Numbers the tracks, if needed, and writes the derived metadata to the
+/// files (or prints it, if it is a dry run). Files, that metadata couldn't
+/// be derived for, are reported and skipped.
+fn write_files(
+    files: &[AudioFile],
+    mut derived: Vec<anyhow::Result<Metadata>>,
+    args: &WriteArgs,
+    options: &ProcessOptions,
+    failures: &mut Failures,
+) {
+    // Numbering depends on the whole batch, so it can be done only after
+    // metadata of all the files is derived
+    if let Some(order) = args.auto_track {
+        let entries = files
+            .iter()
+            .zip(&mut derived)
+            .filter_map(|(file, metadata)| {
+                Some((file, metadata.as_mut().ok()?))
+            })
+            .collect();
+
+        autotrack::number_tracks(entries, order, args.restart_tracks);
     }
 
-    for file in files {
-        if let Err(e) = file.process_file(metadata, mode, &filename_parse_mode)
-        {
-            eprintln!("{e}");
+    for (file, metadata) in files.iter().zip(derived) {
+        let result = metadata.and_then(|x| {
+            if args.dry_run {
+                print_diff(file, &x, &options.policy, &options.clear)
+            } else {
+                file.write_metadata(&x, &options.policy, &options.clear)
+            }
+        });
+
+        if let Err(e) = result {
+            failures.report(&e);
+        }
+    }
+}
+
+/// Prints the error of the arguments in the same way as clap does and exits.
+fn usage_error(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+    Opts::command().error(kind, message).exit()
+}
+
+/// Prints the difference between metadata, that is written to the file, and
+/// the new one, without writing anything.
+fn print_diff(
+    file: &AudioFile,
+    new: &Metadata,
+    policy: &WritePolicy,
+    clear: &ClearFields,
+) -> anyhow::Result<()> {
+    let changes = file.preview(new, policy, clear)?;
+
+    println!("{}", file.path());
+
+    for FieldChange { name, old, change } in changes {
+        let old_value = old.as_deref().unwrap_or("-");
+
+        match change {
+            Change::Set(new) => println!("  {name:<14}{old_value} -> {new}"),
+            Change::Removed => println!("  {name:<14}{old_value} (removed)"),
+            Change::Unchanged => {
+                println!("  {name:<14}{old_value} (unchanged)")
+            }
         }
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures() {
+        let mut failures = Failures::default();
+        assert_eq!(failures.exit_code, 0);
+
+        failures.report(&fme::Error::Parse("Foo".to_string()).into());
+        failures.report(&fme::Error::Parse("Bar".to_string()).into());
+        assert_eq!(failures.exit_code, 5);
+
+        failures.report(&fme::Error::UnsupportedFormat("Baz".into()).into());
+        assert_eq!(failures.exit_code, 1);
+    }
+
+    fn parsers_of(args: &[&str]) -> Vec<String> {
+        let matches = Opts::command().get_matches_from(args);
+        let Command::Parse(parse_args) =
+            Opts::from_arg_matches(&matches).unwrap().command
+        else {
+            panic!("Expected the parse subcommand");
+        };
+        let matches = matches.subcommand_matches("parse").unwrap();
+
+        filename_parsers(&parse_args, matches)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_filename_parsers() {
+        assert_eq!(
+            parsers_of(&[
+                "fme",
+                "parse",
+                "-e",
+                "(.*)",
+                "-p",
+                "{a} - {t}",
+                "-e",
+                "(.+)",
+                "-p",
+                "{t}",
+                "foo.mp3",
+            ]),
+            [
+                "regex '(.*)'",
+                "pattern '{a} - {t}'",
+                "regex '(.+)'",
+                "pattern '{t}'",
+            ]
+        );
+
+        assert_eq!(
+            parsers_of(&["fme", "parse", "foo.mp3"]).len(),
+            FilenameParser::default_parsers().len()
+        );
+    }
 }
 
 // #[cfg(test)]
@@ -1,94 +1,1717 @@
 use std::ffi::OsStr;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use anyhow::{bail, Context};
-use audiotags::{MimeType, Picture, Tag};
-use id3::Version;
+use clap::ValueEnum;
+use id3::frame::PictureType;
+use id3::{TagLike, Version};
+use lofty::ogg::{
+    OggPictureStorage, OpusFile, SpeexFile, VorbisComments, VorbisFile,
+};
+use lofty::{
+    AudioFile as _, FileType, ParseOptions, Probe, TagExt, TagType,
+    TaggedFileExt,
+};
+use mp4ameta::{ident, Data, Fourcc, FreeformIdent, Img, ImgFmt};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::clean::clean_metadata;
+use crate::error::Error;
+use crate::featured::split_featured_artists;
+use crate::internet::{Lookup, MusicBrainz};
+use crate::parse::{metadata_from_named_groups, ParsePattern};
+use crate::show::{diff, FieldChange};
+use crate::transform::expand_template;
+use crate::{FilenameParser, Mode, ProcessOptions, Source};
+
+#[derive(clap::Args, Clone, Default, Debug, PartialEq)]
+pub struct Metadata {
+    /// Write specified value to the 'title' tag
+    #[arg(long, short)]
+    pub title: Option<String>,
+
+    /// Write specified value to the 'artist' tag
+    #[arg(long, short)]
+    pub artist: Option<String>,
+
+    /// Artists, that are featured in the song, in addition to the main one.
+    /// They are written as separate values of the artist field
+    #[arg(skip)]
+    pub featured_artists: Vec<String>,
+
+    /// Write specified value to the 'album' tag
+    #[arg(long, short = 'm', visible_alias = "at")]
+    pub album_title: Option<String>,
+
+    /// Write specified value to the 'album artist' tag
+    #[arg(long, visible_alias = "aa")]
+    pub album_artist: Option<String>,
+
+    /// Set the image, located at the given path, as an album cover
+    #[arg(long, visible_alias = "ac")]
+    pub album_cover: Option<PathBuf>,
+
+    /// Write specified value to the 'year' tag
+    #[arg(long, short)]
+    pub year: Option<NumberOrToken>,
+
+    /// Write specified value to the 'track number' tag. It can be given
+    /// together with the total number of tracks, like '3/12' or '3 of 12'
+    #[arg(long, short = 'd', visible_alias = "tn")]
+    pub track_number: Option<NumberOrToken>,
+
+    /// Write specified value to the 'total number of tracks' tag
+    #[arg(long, visible_alias = "tt")]
+    pub track_total: Option<u32>,
+
+    /// Write specified value to the 'disc number' tag. It can be given
+    /// together with the total number of discs, like '1/2' or '1 of 2'
+    #[arg(long, visible_alias = "dn")]
+    pub disc_number: Option<NumberOrToken>,
+
+    /// Write specified value to the 'total number of discs' tag
+    #[arg(long, visible_alias = "dt")]
+    pub disc_total: Option<u32>,
+
+    /// Write specified value to the 'genre' tag
+    #[arg(long, short)]
+    pub genre: Option<String>,
+
+    /// Write specified value to the 'composer' tag
+    #[arg(long)]
+    pub composer: Option<String>,
+
+    /// Write specified value to the 'comment' tag
+    #[arg(long)]
+    pub comment: Option<String>,
+
+    /// Write specified value to the 'grouping' tag
+    #[arg(long)]
+    pub grouping: Option<String>,
+
+    /// Write specified value to the 'copyright' tag
+    #[arg(long)]
+    pub copyright: Option<String>,
+
+    /// Write specified value to the 'beats per minute' tag
+    #[arg(long)]
+    pub bpm: Option<NumberOrToken>,
+
+    /// Write specified value to the 'unsynchronized lyrics' tag
+    #[arg(long)]
+    pub lyrics: Option<String>,
+
+    /// Write the value to the field with the given key, like 'isrc=...' or
+    /// 'TXXX:Source=youtube'. Keys isrc, label, barcode, catalog-number,
+    /// conductor, lyricist, remixer, mood, media, language, encoded-by and
+    /// original-date are mapped to the standard fields of each format,
+    /// others are written as custom ones. These fields are always written,
+    /// regardless of the policy
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub custom_fields: Vec<CustomField>,
+}
+
+impl Metadata {
+    /// Main artist together with the featured ones, as they are shown to the
+    /// user.
+    pub fn all_artists(&self) -> Option<String> {
+        let mut artists = vec![self.artist.clone()?];
+        artists.extend(self.featured_artists.iter().cloned());
+
+        Some(artists.join(", "))
+    }
+
+    /// Returns the value of the field, as it is written to the tags.
+    /// Numbers, that are still tokens, have no value.
+    pub fn get(&self, field: Field) -> Option<String> {
+        match field {
+            Field::Artist => self.all_artists(),
+            Field::Cover => self
+                .album_cover
+                .as_ref()
+                .map(|x| x.to_string_lossy().to_string()),
+            Field::TrackTotal => self.track_total.map(|x| x.to_string()),
+            Field::DiscTotal => self.disc_total.map(|x| x.to_string()),
+            _ if field.is_text() => self.text(field).cloned(),
+            _ => self
+                .number(field)
+                .and_then(NumberOrToken::number)
+                .map(|x| x.to_string()),
+        }
+    }
+
+    /// Checks, whether the field has a value, including tokens, that are
+    /// not expanded yet.
+    pub fn is_set(&self, field: Field) -> bool {
+        self.get(field).is_some() || self.number(field).is_some()
+    }
+
+    /// Returns the value of the field, if it is a text one.
+    pub fn text(&self, field: Field) -> Option<&String> {
+        match field {
+            Field::Title => self.title.as_ref(),
+            Field::Artist => self.artist.as_ref(),
+            Field::Album => self.album_title.as_ref(),
+            Field::AlbumArtist => self.album_artist.as_ref(),
+            Field::Genre => self.genre.as_ref(),
+            Field::Composer => self.composer.as_ref(),
+            Field::Comment => self.comment.as_ref(),
+            Field::Grouping => self.grouping.as_ref(),
+            Field::Copyright => self.copyright.as_ref(),
+            Field::Lyrics => self.lyrics.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the field, if it is a text one.
+    pub fn text_mut(&mut self, field: Field) -> Option<&mut Option<String>> {
+        match field {
+            Field::Title => Some(&mut self.title),
+            Field::Artist => Some(&mut self.artist),
+            Field::Album => Some(&mut self.album_title),
+            Field::AlbumArtist => Some(&mut self.album_artist),
+            Field::Genre => Some(&mut self.genre),
+            Field::Composer => Some(&mut self.composer),
+            Field::Comment => Some(&mut self.comment),
+            Field::Grouping => Some(&mut self.grouping),
+            Field::Copyright => Some(&mut self.copyright),
+            Field::Lyrics => Some(&mut self.lyrics),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of the field, if it is a number, that can be given
+    /// as a token.
+    fn number(&self, field: Field) -> Option<&NumberOrToken> {
+        match field {
+            Field::Year => self.year.as_ref(),
+            Field::Track => self.track_number.as_ref(),
+            Field::Disc => self.disc_number.as_ref(),
+            Field::Bpm => self.bpm.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn number_mut(
+        &mut self,
+        field: Field,
+    ) -> Option<&mut Option<NumberOrToken>> {
+        match field {
+            Field::Year => Some(&mut self.year),
+            Field::Track => Some(&mut self.track_number),
+            Field::Disc => Some(&mut self.disc_number),
+            Field::Bpm => Some(&mut self.bpm),
+            _ => None,
+        }
+    }
+
+    /// Exchanges the values of the field in this metadata and the given one.
+    fn swap(&mut self, other: &mut Metadata, field: Field) {
+        use std::mem::swap;
+
+        match field {
+            Field::Artist => {
+                swap(&mut self.artist, &mut other.artist);
+                swap(&mut self.featured_artists, &mut other.featured_artists);
+            }
+            Field::Cover => swap(&mut self.album_cover, &mut other.album_cover),
+            Field::TrackTotal => {
+                swap(&mut self.track_total, &mut other.track_total);
+            }
+            Field::DiscTotal => {
+                swap(&mut self.disc_total, &mut other.disc_total);
+            }
+            _ => {
+                if let (Some(x), Some(y)) =
+                    (self.text_mut(field), other.text_mut(field))
+                {
+                    swap(x, y);
+                }
+                if let (Some(x), Some(y)) =
+                    (self.number_mut(field), other.number_mut(field))
+                {
+                    swap(x, y);
+                }
+            }
+        }
+    }
+
+    /// Merges the given metadata into this one: fields, that are set in
+    /// the given metadata, overwrite the current values.
+    pub fn update(&mut self, metadata: &Metadata) {
+        let mut metadata = metadata.clone();
+
+        for field in Field::value_variants() {
+            if metadata.is_set(*field) {
+                self.swap(&mut metadata, *field);
+            }
+        }
+
+        for field in metadata.custom_fields {
+            match self.custom_fields.iter_mut().find(|x| x.key == field.key) {
+                Some(x) => x.value = field.value,
+                None => self.custom_fields.push(field),
+            }
+        }
+    }
+
+    /// Removes the value of the field.
+    pub fn remove(&mut self, field: Field) {
+        self.swap(&mut Metadata::default(), field);
+    }
+
+    /// Checks, that the number fields don't have tokens, that are left
+    /// unexpanded or aren't numbers at all.
+    pub fn check_numbers(&self) -> anyhow::Result<()> {
+        let fields = [
+            (Field::Year, &self.year),
+            (Field::Track, &self.track_number),
+            (Field::Disc, &self.disc_number),
+            (Field::Bpm, &self.bpm),
+        ];
+
+        for (field, value) in fields {
+            if let Some(NumberOrToken::Token(x)) = value {
+                return Err(Error::Parse(format!(
+                    "Value '{x}' of the field '{}' is not a number",
+                    field.name()
+                ))
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the totals, that are given together with the track or disc
+    /// number, like '3/12', to their own fields, unless they are already
+    /// set.
+    pub fn split_totals(&mut self) {
+        if let Some(NumberOrToken::NumberOf(number, total)) = self.track_number
+        {
+            self.track_number = Some(NumberOrToken::Number(number));
+            self.track_total.get_or_insert(total);
+        }
+
+        if let Some(NumberOrToken::NumberOf(number, total)) = self.disc_number {
+            self.disc_number = Some(NumberOrToken::Number(number));
+            self.disc_total.get_or_insert(total);
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Cover,
+    Year,
+    Track,
+    TrackTotal,
+    Disc,
+    DiscTotal,
+    Genre,
+    Composer,
+    Comment,
+    Grouping,
+    Copyright,
+    Bpm,
+    Lyrics,
+}
+
+impl Field {
+    /// Name of the field, as it is given on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Title => "title",
+            Self::Artist => "artist",
+            Self::Album => "album",
+            Self::AlbumArtist => "album-artist",
+            Self::Cover => "cover",
+            Self::Year => "year",
+            Self::Track => "track",
+            Self::TrackTotal => "track-total",
+            Self::Disc => "disc",
+            Self::DiscTotal => "disc-total",
+            Self::Genre => "genre",
+            Self::Composer => "composer",
+            Self::Comment => "comment",
+            Self::Grouping => "grouping",
+            Self::Copyright => "copyright",
+            Self::Bpm => "bpm",
+            Self::Lyrics => "lyrics",
+        }
+    }
+
+    /// Name of the field, as it is shown to the user.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Title => "Title",
+            Self::Artist => "Artist",
+            Self::Album => "Album",
+            Self::AlbumArtist => "Album artist",
+            Self::Cover => "Cover",
+            Self::Year => "Year",
+            Self::Track => "Track",
+            Self::TrackTotal => "Track total",
+            Self::Disc => "Disc",
+            Self::DiscTotal => "Disc total",
+            Self::Genre => "Genre",
+            Self::Composer => "Composer",
+            Self::Comment => "Comment",
+            Self::Grouping => "Grouping",
+            Self::Copyright => "Copyright",
+            Self::Bpm => "BPM",
+            Self::Lyrics => "Lyrics",
+        }
+    }
+
+    /// Number, that the total belongs to, e.g. the track for the total
+    /// number of tracks.
+    fn number_field(self) -> Self {
+        match self {
+            Self::TrackTotal => Self::Track,
+            Self::DiscTotal => Self::Disc,
+            _ => self,
+        }
+    }
+
+    /// Fields, that can have several values, like several artists of a song
+    pub fn is_multi_value(self) -> bool {
+        matches!(
+            self,
+            Self::Artist | Self::AlbumArtist | Self::Genre | Self::Composer
+        )
+    }
+
+    pub fn is_text(self) -> bool {
+        !matches!(
+            self,
+            Self::Cover
+                | Self::Year
+                | Self::Track
+                | Self::TrackTotal
+                | Self::Disc
+                | Self::DiscTotal
+                | Self::Bpm
+        )
+    }
+
+    fn id3_frame(self) -> &'static str {
+        match self {
+            Self::Title => "TIT2",
+            Self::Artist => "TPE1",
+            Self::Album => "TALB",
+            Self::AlbumArtist => "TPE2",
+            Self::Cover => "APIC",
+            Self::Year => "TDRC",
+            // Total number of tracks is stored in the same frame as the
+            // track number, like '3/12'
+            Self::Track | Self::TrackTotal => "TRCK",
+            Self::Disc | Self::DiscTotal => "TPOS",
+            Self::Genre => "TCON",
+            Self::Composer => "TCOM",
+            Self::Comment => "COMM",
+            Self::Grouping => "TIT1",
+            Self::Copyright => "TCOP",
+            Self::Bpm => "TBPM",
+            Self::Lyrics => "USLT",
+        }
+    }
+
+    fn vorbis_key(self) -> &'static str {
+        match self {
+            Self::Title => "TITLE",
+            Self::Artist => "ARTIST",
+            Self::Album => "ALBUM",
+            Self::AlbumArtist => "ALBUMARTIST",
+            Self::Cover => "METADATA_BLOCK_PICTURE",
+            Self::Year => "DATE",
+            Self::Track => "TRACKNUMBER",
+            Self::TrackTotal => "TRACKTOTAL",
+            Self::Disc => "DISCNUMBER",
+            Self::DiscTotal => "DISCTOTAL",
+            Self::Genre => "GENRE",
+            Self::Composer => "COMPOSER",
+            Self::Comment => "COMMENT",
+            Self::Grouping => "GROUPING",
+            Self::Copyright => "COPYRIGHT",
+            Self::Bpm => "BPM",
+            Self::Lyrics => "LYRICS",
+        }
+    }
+
+    fn mp4_ident(self) -> Fourcc {
+        match self {
+            Self::Title => ident::TITLE,
+            Self::Artist => ident::ARTIST,
+            Self::Album => ident::ALBUM,
+            Self::AlbumArtist => ident::ALBUM_ARTIST,
+            Self::Cover => ident::ARTWORK,
+            Self::Year => ident::YEAR,
+            Self::Track | Self::TrackTotal => ident::TRACK_NUMBER,
+            Self::Disc | Self::DiscTotal => ident::DISC_NUMBER,
+            Self::Genre => ident::CUSTOM_GENRE,
+            Self::Composer => ident::COMPOSER,
+            Self::Comment => ident::COMMENT,
+            Self::Grouping => ident::GROUPING,
+            Self::Copyright => ident::COPYRIGHT,
+            Self::Bpm => ident::BPM,
+            Self::Lyrics => ident::LYRICS,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Write the new value, replacing the existing one
+    #[default]
+    Overwrite,
+
+    /// Write the new value only if the field is empty
+    FillMissing,
+
+    /// Never change the field
+    Keep,
+}
+
+/// Decides which of the fields are allowed to be written, depending on what
+/// is already written to the file.
+#[derive(clap::Args, Clone, Default, Debug)]
+pub struct WritePolicy {
+    /// Set the default policy of writing fields, that already have a value
+    #[arg(long, value_enum, default_value_t)]
+    pub policy: Policy,
+
+    /// Never change the given fields
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    pub keep: Vec<Field>,
+
+    /// Write the given fields only if they are empty
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    pub fill_missing: Vec<Field>,
+
+    /// Always write the given fields
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    pub overwrite: Vec<Field>,
+}
+
+impl WritePolicy {
+    pub fn policy_for(&self, field: Field) -> Policy {
+        if self.keep.contains(&field) {
+            Policy::Keep
+        } else if self.fill_missing.contains(&field) {
+            Policy::FillMissing
+        } else if self.overwrite.contains(&field) {
+            Policy::Overwrite
+        } else {
+            self.policy
+        }
+    }
+
+    fn allows(&self, field: Field, is_present: bool) -> bool {
+        match self.policy_for(field) {
+            Policy::Overwrite => true,
+            Policy::FillMissing => !is_present,
+            Policy::Keep => false,
+        }
+    }
+
+    /// Returns metadata with the fields, that are not allowed to be written
+    /// over the existing ones, removed.
+    pub fn filter(
+        &self,
+        metadata: &Metadata,
+        existing: &Metadata,
+        has_cover: bool,
+    ) -> Metadata {
+        let mut metadata = metadata.clone();
+
+        for field in Field::value_variants() {
+            let is_present = match field {
+                Field::Cover => has_cover,
+                _ => existing.is_set(*field),
+            };
+
+            if !self.allows(*field, is_present) {
+                metadata.remove(*field);
+            }
+        }
+
+        metadata
+    }
+}
+
+/// Fields, that are removed from the files before the new values are
+/// written.
+#[derive(clap::Args, Clone, Default, Debug)]
+pub struct ClearFields {
+    /// Remove the given fields from the files. They are not written, even
+    /// if they are derived
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FIELDS")]
+    pub clear: Vec<Field>,
+
+    /// Remove all the fields and frames from the files, including the ones
+    /// fme doesn't know about, before the derived ones are written
+    #[arg(long, conflicts_with = "clear")]
+    pub clear_all: bool,
+}
+
+impl ClearFields {
+    /// Removes the values of the fields, that are cleared explicitly, from
+    /// the derived metadata.
+    pub fn clear_derived(&self, metadata: &mut Metadata) {
+        for field in &self.clear {
+            metadata.remove(*field);
+        }
+    }
+
+    fn clear_tag(&self, tag: &mut dyn AudioTag) {
+        if self.clear_all {
+            tag.clear();
+        } else {
+            for field in &self.clear {
+                tag.remove(*field);
+            }
+        }
+    }
+}
+
+/// Tags and frames, that can be removed with `fme strip`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StripTarget {
+    /// ID3v1 tag at the end of MP3 files
+    Id3v1,
+
+    /// APEv2 tag, that is written to MP3 files by some encoders
+    Apev2,
+
+    /// Private (PRIV) and encapsulated object (GEOB) frames of ID3v2, that
+    /// are left by download tools and encoders
+    Private,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NumberOrToken {
+    Number(u32),
+    /// Number together with the total, like '3/12' or '3 of 12'
+    NumberOf(u32, u32),
+    Token(String),
+}
+
+impl NumberOrToken {
+    pub fn number(&self) -> Option<u32> {
+        match self {
+            Self::Number(n) | Self::NumberOf(n, _) => Some(*n),
+            Self::Token(_) => None,
+        }
+    }
+
+    /// Parses the value of the token after the regex was applied, so it has
+    /// to be a number.
+    fn from_token_value(value: &str, field: &str) -> anyhow::Result<Self> {
+        match value.parse()? {
+            Self::Token(_) => {
+                bail!("You can only put a number in tag '{field}'")
+            }
+            x => Ok(x),
+        }
+    }
+}
+
+impl std::str::FromStr for NumberOrToken {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let is_number =
+            |x: &str| !x.is_empty() && x.chars().all(|x| x.is_ascii_digit());
+
+        if string.chars().all(char::is_numeric) {
+            return Ok(Self::Number(string.parse()?));
+        }
+
+        let number_of = string
+            .split_once('/')
+            .or_else(|| string.split_once(" of "))
+            .map(|(n, total)| (n.trim(), total.trim()))
+            .filter(|(n, total)| is_number(n) && is_number(total));
+
+        match number_of {
+            Some((n, total)) => Ok(Self::NumberOf(n.parse()?, total.parse()?)),
+            None => Ok(Self::Token(string.to_string())),
+        }
+    }
+}
+
+// Keys, that can be given to '--set', and the corresponding identifiers
+// in each of the formats: (key, ID3 frame, Vorbis comment key, name of the
+// MP4 freeform atom). ID3 frames like 'TXXX:NAME' are user-defined text
+// frames with the given description
+const CUSTOM_KEYS: [(&str, &str, &str, &str); 12] = [
+    ("isrc", "TSRC", "ISRC", "ISRC"),
+    ("label", "TPUB", "LABEL", "LABEL"),
+    ("barcode", "TXXX:BARCODE", "BARCODE", "BARCODE"),
+    (
+        "catalog-number",
+        "TXXX:CATALOGNUMBER",
+        "CATALOGNUMBER",
+        "CATALOGNUMBER",
+    ),
+    ("conductor", "TPE3", "CONDUCTOR", "CONDUCTOR"),
+    ("lyricist", "TEXT", "LYRICIST", "LYRICIST"),
+    ("remixer", "TPE4", "REMIXER", "REMIXER"),
+    ("mood", "TMOO", "MOOD", "MOOD"),
+    ("media", "TMED", "MEDIA", "MEDIA"),
+    ("language", "TLAN", "LANGUAGE", "LANGUAGE"),
+    ("encoded-by", "TENC", "ENCODEDBY", "ENCODEDBY"),
+    ("original-date", "TDOR", "ORIGINALDATE", "ORIGINALDATE"),
+];
+
+// Mean of the MP4 freeform atoms, that are written by iTunes and understood
+// by most of the players
+const MP4_FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+/// Value of an arbitrary field, that is given as 'KEY=VALUE' to '--set'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomField {
+    pub key: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for CustomField {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (key, value) = string
+            .split_once('=')
+            .context(format!("Expected 'KEY=VALUE', found '{string}'"))?;
+
+        if key.is_empty() {
+            bail!("Key of the field can't be empty in '{string}'");
+        }
+
+        // User-defined frames can't be written without a description
+        if key == "TXXX" || key == "TXXX:" {
+            bail!("Expected 'TXXX:DESCRIPTION=VALUE', found '{string}'");
+        }
+
+        // Otherwise the field would be written bypassing the policy
+        if let Some(field) = CustomKey::new(key).field() {
+            bail!(
+                "Key '{key}' refers to the field '{}', use its own option \
+                instead",
+                field.name()
+            );
+        }
+
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Identifiers of the custom field in each of the formats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomKey {
+    id3: Id3Key,
+    vorbis: String,
+    mp4: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Id3Key {
+    /// Standard text frame, like 'TPUB'
+    Frame(String),
+    /// User-defined text frame (TXXX) with the given description
+    Extended(String),
+}
+
+impl Id3Key {
+    fn new(frame: &str) -> Self {
+        match frame.strip_prefix("TXXX:") {
+            Some(description) => Self::Extended(description.to_string()),
+            None => Self::Frame(frame.to_string()),
+        }
+    }
+}
+
+impl CustomKey {
+    /// Finds the key in the table of the known keys (case-insensitively).
+    /// Keys like 'TXXX:NAME' and names of the ID3 text frames, like 'TPUB',
+    /// are used for ID3 as they are, other keys (including 'TXXX' itself)
+    /// become user-defined frames.
+    /// Vorbis comments and MP4 freeform atoms use the key itself.
+    pub fn new(key: &str) -> Self {
+        let known = CUSTOM_KEYS
+            .iter()
+            .find(|(x, ..)| x.eq_ignore_ascii_case(key));
+
+        if let Some((_, id3, vorbis, mp4)) = known {
+            return Self {
+                id3: Id3Key::new(id3),
+                vorbis: vorbis.to_string(),
+                mp4: mp4.to_string(),
+            };
+        }
+
+        if let Some(description) = key.strip_prefix("TXXX:") {
+            return Self {
+                id3: Id3Key::Extended(description.to_string()),
+                vorbis: description.to_uppercase(),
+                mp4: description.to_string(),
+            };
+        }
+
+        let is_id3_frame = key.len() == 4
+            && key.starts_with('T')
+            && key != "TXXX"
+            && key
+                .chars()
+                .all(|x| x.is_ascii_uppercase() || x.is_ascii_digit());
+
+        let id3 = if is_id3_frame {
+            Id3Key::Frame(key.to_string())
+        } else {
+            Id3Key::Extended(key.to_string())
+        };
+
+        Self {
+            id3,
+            vorbis: key.to_uppercase(),
+            mp4: key.to_string(),
+        }
+    }
+
+    /// Returns the field, that is stored in the same frame or comment in
+    /// some of the formats.
+    fn field(&self) -> Option<Field> {
+        Field::value_variants().iter().copied().find(|x| {
+            let frame = Id3Key::Frame(x.id3_frame().to_string());
+
+            self.id3 == frame || self.vorbis == x.vorbis_key()
+        })
+    }
+
+    fn mp4_ident(&self) -> FreeformIdent<'_> {
+        FreeformIdent::new(MP4_FREEFORM_MEAN, &self.mp4)
+    }
+}
+
+/// Format-specific representation of the tag.
+#[derive(Debug, Clone)]
+pub struct RawTag {
+    pub format: String,
+    pub has_cover: bool,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Frame {
+    pub id: String,
+    pub value: String,
+}
+
+impl Frame {
+    fn new(id: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            value: value.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cover {
+    pub format: ImageFormat,
+    pub data: Vec<u8>,
+}
+
+impl Cover {
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let ext = path.extension().and_then(OsStr::to_str);
+
+        let format = match ext.map(str::to_lowercase).as_deref() {
+            Some("png") => ImageFormat::Png,
+            Some("jpg" | "jpeg") => ImageFormat::Jpeg,
+            _ => {
+                return Err(Error::UnsupportedFormat(format!(
+                    "Album cover '{}' is neither PNG nor JPEG, other image \
+                    formats are not supported",
+                    path.to_string_lossy()
+                ))
+                .into())
+            }
+        };
+
+        let data = fs::read(path).map_err(|e| Error::io(path, e))?;
+
+        Ok(Self { format, data })
+    }
+}
+
+/// Tag of an audio file, independent of the way it is stored. Each of the
+/// supported formats implements this trait, so the rest of the program
+/// doesn't need to know how fields are called and stored in each of them.
+///
+/// Album cover is binary, so it can't be accessed with `get` and `set`, use
+/// `cover` and `set_cover` for it instead.
+pub trait AudioTag {
+    /// Name of the tag format, e.g. 'ID3v2.4'
+    fn format(&self) -> String;
+
+    fn get(&self, field: Field) -> Option<String>;
+
+    fn set(&mut self, field: Field, value: &str) -> anyhow::Result<()>;
+
+    /// Returns all the values of the field, that can have several ones, like
+    /// the artist
+    fn get_all(&self, field: Field) -> Vec<String>;
+
+    /// Writes several values of the field in the way, that is native for
+    /// the format
+    fn set_all(
+        &mut self,
+        field: Field,
+        values: &[String],
+    ) -> anyhow::Result<()>;
+
+    fn remove(&mut self, field: Field);
+
+    /// Removes all the fields and frames, including the unknown ones
+    fn clear(&mut self);
+
+    /// Returns the value of the field, that is not one of the `Field`s
+    fn get_custom(&self, key: &CustomKey) -> Option<String>;
+
+    fn set_custom(&mut self, key: &CustomKey, value: &str);
+
+    fn cover(&self) -> Option<Cover>;
+
+    fn set_cover(&mut self, cover: Cover);
+
+    /// Lists all the frames of the tag, including the ones that don't
+    /// correspond to any of the fields
+    fn frames(&self) -> Vec<Frame>;
+
+    fn write(&mut self, path: &Path) -> anyhow::Result<()>;
+
+    fn is_present(&self, field: Field) -> bool {
+        match field {
+            Field::Cover => self.cover().is_some(),
+            _ => self.get(field).is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Mp3,
+    Wav,
+    Mp4,
+    Flac,
+    Ogg,
+}
+
+impl FileFormat {
+    fn from_extension(ext: &str) -> Option<Self> {
+        let format = match ext {
+            "mp3" => Self::Mp3,
+            "wav" => Self::Wav,
+            "mp4" | "m4a" | "m4b" | "m4p" | "m4v" | "isom" => Self::Mp4,
+            "flac" => Self::Flac,
+            "ogg" | "oga" | "opus" | "spx" => Self::Ogg,
+            _ => return None,
+        };
+
+        Some(format)
+    }
+
+    /// Reads the tag from the file. If the file doesn't have a tag yet, an
+    /// empty one is created (but not written to the file).
+    fn read_tag(self, path: &Path) -> anyhow::Result<Box<dyn AudioTag>> {
+        let tag: Box<dyn AudioTag> = match self {
+            Self::Mp3 | Self::Wav => Box::new(Id3Tag::read(path, self)?),
+            Self::Mp4 => Box::new(Mp4Tag::read(path)?),
+            Self::Flac => Box::new(FlacTag::read(path)?),
+            Self::Ogg => Box::new(OggTag::read(path)?),
+        };
+
+        Ok(tag)
+    }
+}
+
+struct Id3Tag {
+    tag: id3::Tag,
+    is_wav: bool,
+}
+
+impl Id3Tag {
+    fn read(path: &Path, format: FileFormat) -> anyhow::Result<Self> {
+        let is_wav = format == FileFormat::Wav;
+
+        let tag = if is_wav {
+            id3::Tag::read_from_wav_path(path)
+        } else {
+            id3::Tag::read_from_path(path)
+        };
+
+        let tag = match tag {
+            Ok(tag) => tag,
+            Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => {
+                id3::Tag::with_version(Version::Id3v24)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self { tag, is_wav })
+    }
+}
+
+impl AudioTag for Id3Tag {
+    fn format(&self) -> String {
+        self.tag.version().to_string()
+    }
+
+    fn get(&self, field: Field) -> Option<String> {
+        match field {
+            // Year is stored in different frames in ID3v2.3 and ID3v2.4
+            Field::Year => self
+                .tag
+                .year()
+                .or_else(|| self.tag.date_recorded().map(|x| x.year))
+                .map(|x| x.to_string()),
+            Field::Track => self.tag.track().map(|x| x.to_string()),
+            Field::TrackTotal => self.tag.total_tracks().map(|x| x.to_string()),
+            Field::Disc => self.tag.disc().map(|x| x.to_string()),
+            Field::DiscTotal => self.tag.total_discs().map(|x| x.to_string()),
+            // Comments with a description are usually written by other
+            // programs for their own needs
+            Field::Comment => self
+                .tag
+                .comments()
+                .find(|x| x.description.is_empty())
+                .map(|x| x.text.clone()),
+            Field::Lyrics => self
+                .tag
+                .lyrics()
+                .find(|x| x.description.is_empty())
+                .map(|x| x.text.clone()),
+            Field::Cover => None,
+            _ if field.is_multi_value() => {
+                self.get_all(field).into_iter().next()
+            }
+            _ => self
+                .tag
+                .get(field.id3_frame())
+                .and_then(|x| x.content().text())
+                .map(str::to_string),
+        }
+    }
+
+    fn get_all(&self, field: Field) -> Vec<String> {
+        if !field.is_multi_value() {
+            return self.get(field).into_iter().collect();
+        }
+
+        // ID3v2.4 separates multiple values of the text frames with nulls
+        self.tag
+            .get(field.id3_frame())
+            .and_then(|x| x.content().text())
+            .map(|x| x.split('\0').map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    fn set_all(
+        &mut self,
+        field: Field,
+        values: &[String],
+    ) -> anyhow::Result<()> {
+        match values {
+            [value] => self.set(field, value)?,
+            _ if field.is_multi_value() => {
+                self.tag.set_text_values(field.id3_frame(), values);
+            }
+            _ => bail!("Field '{}' can't have several values", field.name()),
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, field: Field, value: &str) -> anyhow::Result<()> {
+        match field {
+            Field::Year => {
+                self.tag.remove("TYER");
+                self.tag.set_text(field.id3_frame(), value);
+            }
+            // Setting track this way keeps the total number of tracks
+            Field::Track => self.tag.set_track(value.parse()?),
+            Field::Disc => self.tag.set_disc(value.parse()?),
+            // Totals are stored in the same frame as the numbers, so without
+            // a number, the library would write '1' in place of it
+            Field::TrackTotal | Field::DiscTotal
+                if self.get(field.number_field()).is_none() =>
+            {
+                bail!(
+                    "Field '{}' can't be written without '{}' in ID3 tags",
+                    field.name(),
+                    field.number_field().name()
+                )
+            }
+            Field::TrackTotal => self.tag.set_total_tracks(value.parse()?),
+            Field::DiscTotal => self.tag.set_total_discs(value.parse()?),
+            Field::Comment => {
+                self.tag.remove_comment(Some(""), None);
+                self.tag.add_frame(id3::frame::Comment {
+                    lang: "eng".to_string(),
+                    description: String::new(),
+                    text: value.to_string(),
+                });
+            }
+            Field::Lyrics => {
+                self.tag.remove_all_lyrics();
+                self.tag.add_frame(id3::frame::Lyrics {
+                    lang: "eng".to_string(),
+                    description: String::new(),
+                    text: value.to_string(),
+                });
+            }
+            Field::Cover => bail!("Album cover can't be set from text"),
+            _ => self.tag.set_text(field.id3_frame(), value),
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field {
+            Field::Year => {
+                self.tag.remove("TYER");
+                self.tag.remove(field.id3_frame());
+            }
+            Field::TrackTotal => self.tag.remove_total_tracks(),
+            Field::DiscTotal => self.tag.remove_total_discs(),
+            Field::Cover => {
+                self.tag.remove_picture_by_type(PictureType::CoverFront);
+            }
+            Field::Comment => {
+                self.tag.remove_comment(Some(""), None);
+            }
+            Field::Lyrics => self.tag.remove_all_lyrics(),
+            _ => {
+                self.tag.remove(field.id3_frame());
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.tag = id3::Tag::with_version(Version::Id3v24);
+    }
+
+    fn get_custom(&self, key: &CustomKey) -> Option<String> {
+        match &key.id3 {
+            Id3Key::Frame(id) => self
+                .tag
+                .get(id)
+                .and_then(|x| x.content().text())
+                .map(str::to_string),
+            Id3Key::Extended(description) => self
+                .tag
+                .extended_texts()
+                .find(|x| &x.description == description)
+                .map(|x| x.value.clone()),
+        }
+    }
+
+    fn set_custom(&mut self, key: &CustomKey, value: &str) {
+        match &key.id3 {
+            Id3Key::Frame(id) => self.tag.set_text(id, value),
+            Id3Key::Extended(description) => {
+                self.tag
+                    .remove_extended_text(Some(description.as_str()), None);
+                self.tag.add_frame(id3::frame::ExtendedText {
+                    description: description.clone(),
+                    value: value.to_string(),
+                });
+            }
+        }
+    }
+
+    fn cover(&self) -> Option<Cover> {
+        let picture = self
+            .tag
+            .pictures()
+            .find(|x| x.picture_type == PictureType::CoverFront)
+            .or_else(|| self.tag.pictures().next())?;
+
+        let format = match picture.mime_type.as_str() {
+            "image/png" => ImageFormat::Png,
+            _ => ImageFormat::Jpeg,
+        };
+
+        Some(Cover {
+            format,
+            data: picture.data.clone(),
+        })
+    }
+
+    fn set_cover(&mut self, cover: Cover) {
+        self.tag.remove_picture_by_type(PictureType::CoverFront);
+        self.tag.add_frame(id3::frame::Picture {
+            mime_type: cover.format.mime_type().to_string(),
+            picture_type: PictureType::CoverFront,
+            description: String::new(),
+            data: cover.data,
+        });
+    }
+
+    fn frames(&self) -> Vec<Frame> {
+        self.tag
+            .frames()
+            .map(|frame| {
+                let value = match frame.content().picture() {
+                    Some(picture) => format!(
+                        "{:?} ({}, {} bytes)",
+                        picture.picture_type,
+                        picture.mime_type,
+                        picture.data.len()
+                    ),
+                    None => frame.content().to_string(),
+                };
+
+                Frame::new(frame.id(), value)
+            })
+            .collect()
+    }
+
+    fn write(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.is_wav {
+            self.tag.write_to_wav_path(path, Version::Id3v24)?;
+        } else {
+            self.tag.write_to_path(path, Version::Id3v24)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Mp4Tag {
+    tag: mp4ameta::Tag,
+}
+
+impl Mp4Tag {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let tag = mp4ameta::Tag::read_from_path(path)?;
+
+        Ok(Self { tag })
+    }
+}
+
+impl AudioTag for Mp4Tag {
+    fn format(&self) -> String {
+        "MP4".to_string()
+    }
+
+    fn get(&self, field: Field) -> Option<String> {
+        match field {
+            Field::Track => self.tag.track_number().map(|x| x.to_string()),
+            Field::TrackTotal => self.tag.total_tracks().map(|x| x.to_string()),
+            Field::Disc => self.tag.disc_number().map(|x| x.to_string()),
+            Field::DiscTotal => self.tag.total_discs().map(|x| x.to_string()),
+            // Genre can be stored either as text or as a number of one of
+            // the standard genres
+            Field::Genre => self.tag.genre().map(str::to_string),
+            // Tempo is stored as an integer
+            Field::Bpm => self.tag.bpm().map(|x| x.to_string()),
+            Field::Cover => None,
+            _ => self
+                .tag
+                .strings_of(&field.mp4_ident())
+                .next()
+                .map(str::to_string),
+        }
+    }
+
+    fn get_all(&self, field: Field) -> Vec<String> {
+        if !field.is_multi_value() {
+            return self.get(field).into_iter().collect();
+        }
+
+        self.tag
+            .strings_of(&field.mp4_ident())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn set_all(
+        &mut self,
+        field: Field,
+        values: &[String],
+    ) -> anyhow::Result<()> {
+        match values {
+            [value] => self.set(field, value)?,
+            _ if field.is_multi_value() => {
+                self.tag.remove_data_of(&field.mp4_ident());
+
+                for value in values {
+                    self.tag
+                        .add_data(field.mp4_ident(), Data::Utf8(value.clone()));
+                }
+            }
+            _ => bail!("Field '{}' can't have several values", field.name()),
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, field: Field, value: &str) -> anyhow::Result<()> {
+        match field {
+            Field::Track => self.tag.set_track_number(value.parse()?),
+            Field::TrackTotal => self.tag.set_total_tracks(value.parse()?),
+            Field::Disc => self.tag.set_disc_number(value.parse()?),
+            Field::DiscTotal => self.tag.set_total_discs(value.parse()?),
+            Field::Genre => self.tag.set_genre(value),
+            Field::Bpm => self.tag.set_bpm(value.parse()?),
+            Field::Cover => bail!("Album cover can't be set from text"),
+            _ => self
+                .tag
+                .set_data(field.mp4_ident(), Data::Utf8(value.to_string())),
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field {
+            Field::Track => self.tag.remove_track_number(),
+            Field::TrackTotal => self.tag.remove_total_tracks(),
+            Field::Disc => self.tag.remove_disc_number(),
+            Field::DiscTotal => self.tag.remove_total_discs(),
+            Field::Genre => self.tag.remove_genres(),
+            Field::Bpm => self.tag.remove_bpm(),
+            Field::Cover => self.tag.remove_artworks(),
+            _ => self.tag.remove_data_of(&field.mp4_ident()),
+        }
+    }
+
+    fn clear(&mut self) {
+        let idents: Vec<_> = self.tag.data().map(|(x, _)| x.clone()).collect();
+
+        for ident in idents {
+            self.tag.remove_data_of(&ident);
+        }
+    }
+
+    fn get_custom(&self, key: &CustomKey) -> Option<String> {
+        self.tag
+            .strings_of(&key.mp4_ident())
+            .next()
+            .map(str::to_string)
+    }
+
+    fn set_custom(&mut self, key: &CustomKey, value: &str) {
+        self.tag
+            .set_data(key.mp4_ident(), Data::Utf8(value.to_string()));
+    }
+
+    fn cover(&self) -> Option<Cover> {
+        let artwork = self.tag.artwork()?;
+
+        let format = match artwork.fmt {
+            ImgFmt::Png => ImageFormat::Png,
+            _ => ImageFormat::Jpeg,
+        };
+
+        Some(Cover {
+            format,
+            data: artwork.data.to_vec(),
+        })
+    }
+
+    fn set_cover(&mut self, cover: Cover) {
+        let artwork = match cover.format {
+            ImageFormat::Png => Img::png(cover.data),
+            ImageFormat::Jpeg => Img::jpeg(cover.data),
+        };
+
+        self.tag.set_artwork(artwork);
+    }
+
+    fn frames(&self) -> Vec<Frame> {
+        self.tag
+            .data()
+            .map(|(ident, data)| {
+                let value = match data.string() {
+                    Some(x) => x.to_string(),
+                    None if data.is_image() => "<image>".to_string(),
+                    None => format!("{data:?}"),
+                };
+
+                Frame::new(ident.to_string(), value)
+            })
+            .collect()
+    }
+
+    fn write(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.tag.write_to_path(path)?;
+
+        Ok(())
+    }
+}
+
+struct FlacTag {
+    tag: metaflac::Tag,
+}
+
+impl FlacTag {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let tag = metaflac::Tag::read_from_path(path)?;
+
+        Ok(Self { tag })
+    }
+}
+
+impl AudioTag for FlacTag {
+    fn format(&self) -> String {
+        "FLAC (Vorbis comments)".to_string()
+    }
+
+    fn get(&self, field: Field) -> Option<String> {
+        self.tag
+            .get_vorbis(field.vorbis_key())?
+            .next()
+            .map(str::to_string)
+    }
+
+    fn set(&mut self, field: Field, value: &str) -> anyhow::Result<()> {
+        if field == Field::Cover {
+            bail!("Album cover can't be set from text");
+        }
+
+        self.tag.set_vorbis(field.vorbis_key(), vec![value]);
+
+        Ok(())
+    }
+
+    fn get_all(&self, field: Field) -> Vec<String> {
+        self.tag
+            .get_vorbis(field.vorbis_key())
+            .map(|x| x.map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    // Vorbis comments store multiple values as repeated keys
+    fn set_all(
+        &mut self,
+        field: Field,
+        values: &[String],
+    ) -> anyhow::Result<()> {
+        if field == Field::Cover {
+            bail!("Album cover can't be set from text");
+        }
+
+        self.tag.set_vorbis(field.vorbis_key(), values.to_vec());
+
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field {
+            Field::Cover => self
+                .tag
+                .remove_picture_type(metaflac::block::PictureType::CoverFront),
+            _ => self.tag.remove_vorbis(field.vorbis_key()),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.tag.remove_blocks(metaflac::BlockType::VorbisComment);
+        self.tag.remove_blocks(metaflac::BlockType::Picture);
+    }
+
+    fn get_custom(&self, key: &CustomKey) -> Option<String> {
+        self.tag.get_vorbis(&key.vorbis)?.next().map(str::to_string)
+    }
+
+    fn set_custom(&mut self, key: &CustomKey, value: &str) {
+        self.tag.set_vorbis(&key.vorbis, vec![value]);
+    }
+
+    fn cover(&self) -> Option<Cover> {
+        let picture = self.tag.pictures().next()?;
+
+        let format = match picture.mime_type.as_str() {
+            "image/png" => ImageFormat::Png,
+            _ => ImageFormat::Jpeg,
+        };
+
+        Some(Cover {
+            format,
+            data: picture.data.clone(),
+        })
+    }
+
+    fn set_cover(&mut self, cover: Cover) {
+        let picture_type = metaflac::block::PictureType::CoverFront;
+
+        self.tag.remove_picture_type(picture_type);
+        self.tag.add_picture(
+            cover.format.mime_type(),
+            picture_type,
+            cover.data,
+        );
+    }
+
+    fn frames(&self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+
+        if let Some(comments) = self.tag.vorbis_comments() {
+            for (key, values) in &comments.comments {
+                for value in values {
+                    frames.push(Frame::new(key, value));
+                }
+            }
+        }
+
+        frames.sort_by(|a, b| a.id.cmp(&b.id));
+
+        for picture in self.tag.pictures() {
+            frames.push(Frame::new(
+                "METADATA_BLOCK_PICTURE",
+                format!(
+                    "{:?} ({}, {} bytes)",
+                    picture.picture_type,
+                    picture.mime_type,
+                    picture.data.len()
+                ),
+            ));
+        }
 
-use crate::parse::ParsePattern;
-use crate::{FilenameParseMode, Mode};
+        frames
+    }
 
-#[derive(clap::Args, Clone, Default, Debug)]
-pub struct Metadata {
-    /// Write specified value to the 'title' tag
-    #[arg(long, short)]
-    pub title: Option<String>,
+    fn write(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.tag.write_to_path(path)?;
 
-    /// Write specified value to the 'artist' tag
-    #[arg(long, short)]
-    pub artist: Option<String>,
+        Ok(())
+    }
+}
 
-    /// Write specified value to the 'album' tag
-    #[arg(long, short = 'm', visible_alias = "at")]
-    pub album_title: Option<String>,
+struct OggTag {
+    comments: VorbisComments,
+    file_type: FileType,
+}
 
-    /// Set the image, located at the given path, as an album cover
-    #[arg(long, visible_alias = "ac")]
-    pub album_cover: Option<PathBuf>,
+impl OggTag {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let file_type = Probe::open(path)?.guess_file_type()?.file_type();
+        let mut file = fs::File::open(path)?;
+        let options = ParseOptions::new();
+
+        // Ogg files always have Vorbis comments header, so there is no need
+        // to create it as for the other formats
+        let (file_type, comments) = match file_type {
+            Some(x @ FileType::Vorbis) => {
+                let file = VorbisFile::read_from(&mut file, options)?;
+                (x, file.vorbis_comments().clone())
+            }
 
-    /// Write specified value to the 'year' tag
-    #[arg(long, short)]
-    pub year: Option<NumberOrToken>,
+            Some(x @ FileType::Opus) => {
+                let file = OpusFile::read_from(&mut file, options)?;
+                (x, file.vorbis_comments().clone())
+            }
 
-    /// Write specified value to the 'track number' tag
-    #[arg(long, short = 'd', visible_alias = "tn")]
-    pub track_number: Option<NumberOrToken>,
+            Some(x @ FileType::Speex) => {
+                let file = SpeexFile::read_from(&mut file, options)?;
+                (x, file.vorbis_comments().clone())
+            }
+
+            _ => bail!(
+                "The file '{}' is not an Ogg Vorbis, Opus or Speex file",
+                path.to_string_lossy()
+            ),
+        };
+
+        Ok(Self {
+            comments,
+            file_type,
+        })
+    }
 }
 
-impl Metadata {
-    fn update(&mut self, metadata: &Metadata) {
-        if metadata.artist.is_some() {
-            self.artist = metadata.artist.clone();
+impl AudioTag for OggTag {
+    fn format(&self) -> String {
+        format!("{:?} (Vorbis comments)", self.file_type)
+    }
+
+    fn get(&self, field: Field) -> Option<String> {
+        self.comments.get(field.vorbis_key()).map(str::to_string)
+    }
+
+    fn set(&mut self, field: Field, value: &str) -> anyhow::Result<()> {
+        if field == Field::Cover {
+            bail!("Album cover can't be set from text");
         }
 
-        if metadata.title.is_some() {
-            self.title = metadata.title.clone();
+        self.comments
+            .insert(field.vorbis_key().to_string(), value.to_string());
+
+        Ok(())
+    }
+
+    fn get_all(&self, field: Field) -> Vec<String> {
+        self.comments
+            .get_all(field.vorbis_key())
+            .map(str::to_string)
+            .collect()
+    }
+
+    // Vorbis comments store multiple values as repeated keys
+    fn set_all(
+        &mut self,
+        field: Field,
+        values: &[String],
+    ) -> anyhow::Result<()> {
+        if field == Field::Cover {
+            bail!("Album cover can't be set from text");
         }
 
-        if metadata.album_cover.is_some() {
-            self.album_cover = metadata.album_cover.clone();
+        let _ = self.comments.remove(field.vorbis_key());
+
+        for value in values {
+            self.comments
+                .push(field.vorbis_key().to_string(), value.clone());
         }
 
-        if metadata.album_title.is_some() {
-            self.album_title = metadata.album_title.clone();
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field {
+            Field::Cover => self
+                .comments
+                .remove_picture_type(lofty::PictureType::CoverFront),
+            _ => {
+                let _ = self.comments.remove(field.vorbis_key());
+            }
         }
+    }
 
-        if metadata.year.is_some() {
-            self.year = metadata.year.clone();
+    // Vendor string is kept, because it is a part of the header and not a
+    // field
+    fn clear(&mut self) {
+        let keys: Vec<_> =
+            self.comments.items().map(|(x, _)| x.to_string()).collect();
+
+        for key in keys {
+            let _ = self.comments.remove(&key);
         }
 
-        if metadata.track_number.is_some() {
-            self.track_number = metadata.track_number.clone();
+        while !self.comments.pictures().is_empty() {
+            self.comments.remove_picture(0);
         }
     }
-}
 
-#[derive(Debug, Clone)]
-pub enum NumberOrToken {
-    Number(u32),
-    Token(String),
-}
+    fn get_custom(&self, key: &CustomKey) -> Option<String> {
+        self.comments.get(&key.vorbis).map(str::to_string)
+    }
 
-impl std::str::FromStr for NumberOrToken {
-    type Err = anyhow::Error;
+    fn set_custom(&mut self, key: &CustomKey, value: &str) {
+        self.comments.insert(key.vorbis.clone(), value.to_string());
+    }
 
-    fn from_str(string: &str) -> Result<Self, Self::Err> {
-        if string.chars().all(char::is_numeric) {
-            Ok(Self::Number(string.parse()?))
-        } else {
-            Ok(Self::Token(string.to_string()))
+    fn cover(&self) -> Option<Cover> {
+        let (picture, _) = self.comments.pictures().first()?;
+
+        let format = match picture.mime_type() {
+            lofty::MimeType::Png => ImageFormat::Png,
+            _ => ImageFormat::Jpeg,
+        };
+
+        Some(Cover {
+            format,
+            data: picture.data().to_vec(),
+        })
+    }
+
+    fn set_cover(&mut self, cover: Cover) {
+        let mime_type = match cover.format {
+            ImageFormat::Png => lofty::MimeType::Png,
+            ImageFormat::Jpeg => lofty::MimeType::Jpeg,
+        };
+
+        let picture = lofty::Picture::new_unchecked(
+            lofty::PictureType::CoverFront,
+            mime_type,
+            None,
+            cover.data,
+        );
+
+        self.comments
+            .remove_picture_type(lofty::PictureType::CoverFront);
+        // Picture information is needed only for the pictures with unknown
+        // format, so this can't fail
+        let _ = self.comments.insert_picture(picture, None);
+    }
+
+    fn frames(&self) -> Vec<Frame> {
+        let mut frames: Vec<_> = self
+            .comments
+            .items()
+            .map(|(key, value)| Frame::new(key, value))
+            .collect();
+
+        for (picture, _) in self.comments.pictures() {
+            frames.push(Frame::new(
+                "METADATA_BLOCK_PICTURE",
+                format!(
+                    "{:?} ({}, {} bytes)",
+                    picture.pic_type(),
+                    picture.mime_type().as_str(),
+                    picture.data().len()
+                ),
+            ));
         }
+
+        frames
+    }
+
+    fn write(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.comments.save_to_path(path)?;
+
+        Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct AudioFile {
     path: PathBuf,
+    format: FileFormat,
 }
 
 impl AudioFile {
+    /// Opens the audio file. Format of the file is determined by its
+    /// extension, tags are read only when they are needed.
     pub fn new(file: impl AsRef<Path>) -> anyhow::Result<Self> {
         let ext = match file.as_ref().extension() {
             Some(x) => x.to_string_lossy().to_string().to_lowercase(),
@@ -97,276 +1720,885 @@ impl AudioFile {
 
         let path = file.as_ref().to_owned();
 
-        if !path.is_file() {
-            bail!("There is no such file: '{}'", path.to_string_lossy());
+        let file_type = fs::metadata(&path)
+            .map_err(|e| Error::io(&path, e))?
+            .file_type();
+
+        if !file_type.is_file() {
+            return Err(Error::UnsupportedFormat(format!(
+                "'{}' is not a file",
+                path.to_string_lossy()
+            ))
+            .into());
         }
 
         if ext.is_empty() {
-            bail!(
+            return Err(Error::UnsupportedFormat(format!(
                 "Can't figure out filetype of the file '{}', \
-            because there is no extension",
+                because there is no extension",
                 path.to_string_lossy()
-            );
+            ))
+            .into());
         }
 
-        if !is_supported_type(&ext) {
-            bail!("Filetype '{ext}' is not supported");
-        }
+        let Some(format) = FileFormat::from_extension(&ext) else {
+            return Err(Error::UnsupportedFormat(format!(
+                "Filetype '{ext}' of the file '{}' is not supported",
+                path.to_string_lossy()
+            ))
+            .into());
+        };
 
-        Ok(Self { path })
+        Ok(Self { path, format })
     }
 
-    fn path(&self) -> String {
+    pub fn path(&self) -> String {
         self.path.to_string_lossy().to_string()
     }
 
-    fn filename_stem(&self) -> String {
-        self.path.file_stem().unwrap().to_string_lossy().to_string()
+    pub fn as_path(&self) -> &Path {
+        &self.path
     }
 
-    fn init_metadata(&self) -> anyhow::Result<()> {
-        match self
-            .path
-            .extension()
-            .unwrap()
-            .to_string_lossy()
-            .to_string()
-            .to_lowercase()
-            .as_str()
-        {
-            "mp3" => {
-                let new_tag = id3::Tag::new();
-                new_tag.write_to_path(self.path(), Version::Id3v24)?;
-            }
+    fn read_tag(&self) -> anyhow::Result<Box<dyn AudioTag>> {
+        self.format
+            .read_tag(&self.path)
+            .map_err(|e| self.tag_read_error(e))
+    }
 
-            "wav" => {
-                let new_tag = id3::Tag::new();
-                new_tag.write_to_wav_path(self.path(), Version::Id3v24)?;
-            }
+    fn tag_read_error(
+        &self,
+        source: impl Into<anyhow::Error>,
+    ) -> anyhow::Error {
+        Error::TagRead {
+            path: self.path.clone(),
+            source: source.into(),
+        }
+        .into()
+    }
 
-            "m4a" | "m4b" | "m4p" | "m4v" | "isom" | "mp4" => {
-                let new_tag = mp4ameta::Tag::default();
-                new_tag.write_to_path(self.path())?;
-            }
+    fn tag_write_error(
+        &self,
+        source: impl Into<anyhow::Error>,
+    ) -> anyhow::Error {
+        Error::TagWrite {
+            path: self.path.clone(),
+            source: source.into(),
+        }
+        .into()
+    }
+
+    /// Reads metadata, that is already written to the file's tags.
+    pub fn read_metadata(&self) -> anyhow::Result<Metadata> {
+        Ok(metadata_from_tag(&*self.read_tag()?))
+    }
+
+    /// Reads the value of the field, that was given to '--set'.
+    pub fn read_custom(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.read_tag()?.get_custom(&CustomKey::new(key)))
+    }
 
-            "flac" => {
-                let mut new_tag = metaflac::Tag::new();
-                new_tag.write_to_path(self.path())?;
+    /// Removes the given tags and frames from the file or, if none are
+    /// given, all of its tags. Returns the names of the removed ones.
+    pub fn strip(
+        &self,
+        targets: &[StripTarget],
+    ) -> anyhow::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        let is_id3 = matches!(self.format, FileFormat::Mp3 | FileFormat::Wav);
+
+        if targets.contains(&StripTarget::Private) && is_id3 {
+            let mut tag = Id3Tag::read(&self.path, self.format)
+                .map_err(|e| self.tag_read_error(e))?;
+            let count = tag
+                .tag
+                .frames()
+                .filter(|x| matches!(x.id(), "PRIV" | "GEOB"))
+                .count();
+
+            if count > 0 {
+                tag.tag.remove("PRIV");
+                tag.tag.remove("GEOB");
+                tag.write(&self.path).map_err(|e| self.tag_write_error(e))?;
+
+                removed.push(format!("{count} PRIV/GEOB frame(s)"));
             }
+        }
+
+        let tagged_file = lofty::read_from_path(&self.path)
+            .map_err(|e| self.tag_read_error(e))?;
+
+        let tag_types: Vec<_> = if targets.is_empty() {
+            tagged_file.tags().iter().map(|x| x.tag_type()).collect()
+        } else {
+            targets
+                .iter()
+                .filter_map(|x| match x {
+                    StripTarget::Id3v1 => Some(TagType::Id3v1),
+                    StripTarget::Apev2 => Some(TagType::Ape),
+                    StripTarget::Private => None,
+                })
+                .filter(|x| tagged_file.contains_tag_type(*x))
+                .collect()
+        };
 
-            _ => unimplemented!("Other file formats are not supported"),
+        for tag_type in tag_types {
+            tag_type
+                .remove_from_path(&self.path)
+                .map_err(|e| self.tag_write_error(e))?;
+            removed.push(format!("{tag_type:?} tag"));
         }
 
-        Ok(())
+        Ok(removed)
     }
 
-    pub fn write_metadata(&self, metadata: &Metadata) -> anyhow::Result<()> {
-        let tag = Tag::new();
-        let filename = self.path.file_name().unwrap().to_string_lossy();
+    /// Reads the tag of the file as it is stored in the file, i.e. with all
+    /// the format-specific frames.
+    pub fn read_raw_tag(&self) -> anyhow::Result<RawTag> {
+        let tag = self.read_tag()?;
 
-        let mut tag = if let Ok(tag_import) = tag.read_from_path(&self.path) {
-            tag_import
-        } else {
-            self.init_metadata().context(format!(
-                "Failed to init metadata tags in the file '{filename}'",
-            ))?;
+        Ok(RawTag {
+            format: tag.format(),
+            has_cover: tag.is_present(Field::Cover),
+            frames: tag.frames(),
+        })
+    }
 
-            tag.read_from_path(&self.path).context(format!(
-                "Failed to read metadata tags from the file '{filename}'",
-            ))?
+    pub fn filename_stem(&self) -> String {
+        let stem = self.path.file_stem().unwrap_or_default();
+
+        stem.to_string_lossy().to_string()
+    }
+
+    /// Returns the last `n` components of the path, separated with '/' and
+    /// with extension removed, e.g. 'Artist/2023 - Album/01 - Title' for
+    /// `n = 3`. If the path is shorter, `None` is returned.
+    fn path_tail(&self, n: usize) -> Option<String> {
+        let parent = match self.path.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+            _ => PathBuf::from("."),
         };
 
-        if let Some(artist) = &metadata.artist {
-            tag.set_artist(artist);
-        }
+        // Relative paths have to be resolved to get names of the parent
+        // directories
+        let parent = parent.canonicalize().unwrap_or(parent);
 
-        if let Some(title) = &metadata.title {
-            tag.set_title(title);
-        }
+        let mut components: Vec<_> = parent
+            .components()
+            .filter_map(|x| match x {
+                Component::Normal(x) => Some(x.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
 
-        if let Some(album_cover) = &metadata.album_cover {
-            let cover = fs::read(album_cover).unwrap();
-            let ext = album_cover.extension().and_then(OsStr::to_str).unwrap();
+        components.push(self.filename_stem());
 
-            let mimetype = match ext {
-                "png" | "PNG" => MimeType::Png,
-                "jpg" | "jpeg" | "JPG" => MimeType::Jpeg,
-                _ => unimplemented!(
-                    "Other image formats are not supported for album cover"
-                ),
-            };
+        let start = components.len().checked_sub(n)?;
 
-            let picture = Picture::new(&cover, mimetype);
-            tag.set_album_cover(picture);
-        }
+        Some(components[start..].join("/"))
+    }
 
-        if let Some(album_title) = &metadata.album_title {
-            tag.set_album_title(album_title);
-        }
+    /// Writes the metadata to the tags of the file. The cleared fields are
+    /// removed first, and fields, that the policy doesn't allow to change,
+    /// are left as they are.
+    pub fn write_metadata(
+        &self,
+        metadata: &Metadata,
+        policy: &WritePolicy,
+        clear: &ClearFields,
+    ) -> anyhow::Result<()> {
+        metadata.check_numbers()?;
 
-        if let Some(year) = &metadata.year {
-            if let NumberOrToken::Number(n) = year {
-                tag.set_year(*n as i32);
-            } else {
-                bail!(
-                    "Can't write token to metadata tag 'year', \
-                something went wrong in the program. Please, report a bug."
-                );
-            }
-        }
+        let mut tag = self.read_tag()?;
+        apply_metadata(&mut *tag, metadata, policy, clear)?;
+        tag.write(&self.path).map_err(|e| self.tag_write_error(e))?;
 
-        if let Some(track_number) = &metadata.track_number {
-            if let NumberOrToken::Number(t) = track_number {
-                tag.set_track_number(*t as u16);
-            } else {
-                bail!(
-                    "Can't write token to metadata tag 'track_number', \
-                something went wrong in the program. Please, report a bug."
-                );
+        Ok(())
+    }
+
+    /// Derives metadata of the file, trying the given regexes and patterns
+    /// in order. Returns it together with the place, it was derived from.
+    pub fn derive_metadata(
+        &self,
+        options: &ProcessOptions,
+        filename_parsers: &[FilenameParser],
+    ) -> anyhow::Result<(Metadata, Source)> {
+        let (mut derived_metadata, source) = match options.mode {
+            Mode::FromFilename => {
+                let (mut parsed, specified, parser) = self
+                    .metadata_from_filename(
+                        &options.metadata,
+                        filename_parsers,
+                    )?;
+                parsed.update(&specified);
+
+                (parsed, Source::Filename(parser.clone()))
             }
-        }
 
+            Mode::FromInternet => (
+                self.metadata_from_internet(
+                    &options.metadata,
+                    filename_parsers,
+                    &options.musicbrainz,
+                )?,
+                Source::MusicBrainz,
+            ),
+        };
 
-        tag.write_to_path(&self.path()).context(format!(
-            "Failed to write metadata tags in the file '{filename}'",
-        ))?;
+        if options.split_featured {
+            split_featured_artists(&mut derived_metadata);
+        }
 
-        Ok(())
+        clean_metadata(&options.clean_rules, &mut derived_metadata);
+        derived_metadata.split_totals();
+
+        Ok((derived_metadata, source))
     }
 
-    pub fn process_file(
+    /// Returns the changes of the fields, that writing the metadata with the
+    /// given policy would make, without writing anything. The metadata is
+    /// written to the tag in memory in the same way as by `write_metadata`,
+    /// so it fails in the same cases.
+    pub fn preview(
         &self,
         metadata: &Metadata,
-        mode: Mode,
-        filename_parse_mode: &FilenameParseMode,
-    ) -> anyhow::Result<()> {
-        let try_derive_metadata;
-
-        match mode {
-            Mode::FromFilename => match filename_parse_mode {
-                FilenameParseMode::Parser(parse_patterns) => {
-                    match self.parse_metadata_from_filename(parse_patterns) {
-                        Ok(x) => try_derive_metadata = Some(x),
-                        Err(e) => bail!(
-                            "Couldn't apply given patterns to the filename '{}', \
-                            the following error occurred: {e}",
-                            self.filename_stem()
-                        ),
-                    };
-                }
+        policy: &WritePolicy,
+        clear: &ClearFields,
+    ) -> anyhow::Result<Vec<FieldChange>> {
+        metadata.check_numbers()?;
 
-                FilenameParseMode::Regex(regex) => {
-                    match self.regex_metadata_from_filename(regex, metadata) {
-                        Ok(x) => try_derive_metadata = Some(x),
-                        Err(e) => bail!(
-                            "Couldn't apply given regex to the filename '{}', \
-                            the following error occurred: {e}",
-                            self.filename_stem()
-                        ),
-                    };
-                }
-            },
+        let mut tag = self.read_tag()?;
+        let old_cover = tag.cover();
+        let old = metadata_with_custom(&*tag, &metadata.custom_fields);
 
-            Mode::FromInternet => todo!(), //self.metadata_from_internet(),
-        };
+        apply_metadata(&mut *tag, metadata, policy, clear)?;
 
-        if let Some(mut derived_metadata) = try_derive_metadata {
-            match filename_parse_mode {
-                // We don't want to write specified metadata in case of regex,
-                // because it has been already written with needed tokens applied
-                FilenameParseMode::Regex(_) => {}
+        let new_cover = tag.cover();
+        let new = metadata_with_custom(&*tag, &metadata.custom_fields);
 
-                _ => derived_metadata.update(metadata),
+        let embedded = |x: &Option<Cover>| {
+            x.as_ref().map(|_| "<embedded image>".to_string())
+        };
+        let is_replaced = match (&old_cover, &new_cover) {
+            (Some(old), Some(new)) => old.data != new.data,
+            (old, new) => old.is_none() && new.is_some(),
+        };
+        let new_cover_value = match &metadata.album_cover {
+            Some(path) if is_replaced => {
+                Some(path.to_string_lossy().to_string())
             }
+            _ => embedded(&new_cover),
+        };
+
+        Ok(diff(&old, embedded(&old_cover), &new, new_cover_value))
+    }
 
-            self.write_metadata(&derived_metadata)?;
+    /// Tries the given regexes and patterns in order and returns metadata
+    /// parsed by the first matching one, specified metadata (with regex
+    /// tokens applied) and the matched regex or pattern itself.
+    fn metadata_from_filename<'a>(
+        &self,
+        metadata: &Metadata,
+        filename_parsers: &'a [FilenameParser],
+    ) -> anyhow::Result<(Metadata, Metadata, &'a FilenameParser)> {
+        let mut errors = Vec::new();
+
+        for parser in filename_parsers {
+            match self.apply_filename_parser(parser, metadata) {
+                Ok((parsed, specified)) => {
+                    return Ok((parsed, specified, parser))
+                }
+                Err(e) => errors.push(format!("{parser}: {e}")),
+            }
         }
 
-        Ok(())
+        Err(Error::Parse(format!(
+            "Couldn't apply any of the given regexes and patterns to the \
+            filename '{}', the following errors occurred:\n  {}",
+            self.filename_stem(),
+            errors.join("\n  ")
+        ))
+        .into())
+    }
+
+    /// Returns metadata parsed from the filename by the given regex or
+    /// pattern and the specified metadata with the tokens of the regex
+    /// applied. Patterns have no groups, so they fail, if the specified
+    /// metadata has any tokens.
+    pub fn apply_filename_parser(
+        &self,
+        parser: &FilenameParser,
+        metadata: &Metadata,
+    ) -> anyhow::Result<(Metadata, Metadata)> {
+        match parser {
+            FilenameParser::Pattern(pattern) => {
+                let parsed = self.pattern_metadata_from_filename(pattern)?;
+                let specified = expand_tokens(metadata, |_: &str| None)?;
+
+                Ok((parsed, specified))
+            }
+
+            FilenameParser::Regex(regex) => {
+                self.regex_metadata_from_filename(regex, metadata)
+            }
+        }
     }
 
+    /// Returns metadata from the named groups of the regex and the specified
+    /// metadata with the tokens of the regex applied.
     fn regex_metadata_from_filename(
         &self,
         regex: &str,
         metadata: &Metadata,
-    ) -> anyhow::Result<Metadata> {
+    ) -> anyhow::Result<(Metadata, Metadata)> {
         let filename_stem = self.filename_stem();
-        let mut metadata = metadata.clone();
 
         let re = Regex::new(regex)?;
-        let captures = re.captures(&filename_stem).context(
-            "Couldn't apply regex to this filename: {filename_stem}",
-        )?;
-
-        for i in 1..captures.len() {
-            let token = format!("${{{i}}}");
-            let replace_token = &captures[i];
+        let captures = re.captures(&filename_stem).context(format!(
+            "Couldn't apply regex to this filename: {filename_stem}"
+        ))?;
 
-            if let Some(artist) = metadata.artist {
-                metadata.artist = Some(artist.replace(&token, replace_token));
+        // Groups can be referred both by their numbers and names
+        let value_of = |name: &str| {
+            match name.parse() {
+                Ok(i) => captures.get(i),
+                Err(_) => captures.name(name),
             }
+            .map(|x| x.as_str())
+        };
 
-            if let Some(title) = metadata.title {
-                metadata.title = Some(title.replace(&token, replace_token));
-            }
+        let metadata = expand_tokens(metadata, value_of)?;
 
-            if let Some(NumberOrToken::Token(track_number)) =
-                metadata.track_number
-            {
-                let track_number = track_number
-                    .to_string()
-                    .replace(&token, replace_token)
-                    .parse()
-                    .context(
-                        "You can only put a number in tag 'track_number'",
-                    )?;
+        // Named groups fill in the corresponding fields, unless they are
+        // specified explicitly
+        let derived_metadata = metadata_from_named_groups(&re, &captures)?;
+
+        Ok((derived_metadata, metadata))
+    }
 
-                metadata.track_number =
-                    Some(NumberOrToken::Number(track_number));
+    fn pattern_metadata_from_filename(
+        &self,
+        pattern: &ParsePattern,
+    ) -> anyhow::Result<Metadata> {
+        let input = self
+            .path_tail(pattern.depth())
+            .context("The path has too few components")?;
+
+        pattern.try_pattern(&input)
+    }
+
+    fn metadata_from_internet(
+        &self,
+        metadata: &Metadata,
+        filename_parsers: &[FilenameParser],
+        musicbrainz: &MusicBrainz,
+    ) -> anyhow::Result<Metadata> {
+        // Existing tags are used only for the fields, that we failed to
+        // derive from the filename
+        let mut query = self.read_metadata().unwrap_or_default();
+
+        let has_regex = filename_parsers
+            .iter()
+            .any(|x| matches!(x, FilenameParser::Regex(_)));
+
+        // Values specified by the user (with regex tokens applied) are used
+        // both for the search and to override the found ones
+        let specified_metadata =
+            match self.metadata_from_filename(metadata, filename_parsers) {
+                Ok((parsed, specified, _)) => {
+                    query.update(&parsed);
+                    specified
+                }
+
+                // Specified values can't be used as they are, if they may
+                // contain tokens of the regexes
+                Err(_) if has_regex => Metadata::default(),
+
+                Err(_) => metadata.clone(),
+            };
+
+        query.update(&specified_metadata);
+
+        match musicbrainz.lookup(&query).context(format!(
+            "Failed to find metadata for the file '{}' in MusicBrainz",
+            self.path()
+        ))? {
+            Lookup::Found(mut x) => {
+                x.update(&specified_metadata);
+                Ok(*x)
             }
+            Lookup::Ambiguous(candidates) => {
+                let candidates = candidates
+                    .iter()
+                    .enumerate()
+                    .map(|(i, x)| format!("{:>4}. {x}", i + 1))
+                    .collect::<Vec<_>>()
+                    .join("\n");
 
-            if let Some(album_title) = metadata.album_title {
-                metadata.album_title =
-                    Some(album_title.replace(&token, replace_token));
+                bail!(
+                    "Found several matching recordings in MusicBrainz for \
+                    the file '{}', specify more metadata to narrow the \
+                    search. Candidates are:\n{candidates}",
+                    self.path()
+                );
             }
+        }
+    }
+}
 
-            if let Some(NumberOrToken::Token(year)) = metadata.year {
-                let year = year
-                    .to_string()
-                    .replace(&token, replace_token)
-                    .parse()
-                    .context("You can only put a number in tag 'year'")?;
+/// Puts the values of the groups in place of the tokens like `${1}` or
+/// `${name|trim}` in the specified metadata.
+fn expand_tokens<'a>(
+    metadata: &Metadata,
+    value_of: impl Fn(&str) -> Option<&'a str>,
+) -> anyhow::Result<Metadata> {
+    let mut metadata = metadata.clone();
+    let apply_tokens = |template: &str| expand_template(template, &value_of);
+
+    for field in Field::value_variants() {
+        if let Some(Some(value)) = metadata.text_mut(*field) {
+            *value = apply_tokens(value)?;
+        }
 
-                metadata.year = Some(NumberOrToken::Number(year));
+        if let Some(number) = metadata.number_mut(*field) {
+            if let Some(NumberOrToken::Token(token)) = number {
+                *number = Some(NumberOrToken::from_token_value(
+                    &apply_tokens(token)?,
+                    field.name(),
+                )?);
             }
         }
+    }
 
-        Ok(metadata)
+    for field in &mut metadata.custom_fields {
+        field.value = apply_tokens(&field.value)?;
     }
 
-    fn parse_metadata_from_filename(
-        &self,
-        parse_patterns: &[ParsePattern],
-    ) -> anyhow::Result<Metadata> {
-        let filename_stem = self.filename_stem();
+    Ok(metadata)
+}
 
-        for pattern in parse_patterns {
-            if let Ok(metadata) = pattern.try_pattern(&filename_stem) {
-                return Ok(metadata);
+/// Writes the metadata to the tag in memory. The cleared fields are removed
+/// first, and fields, that the policy doesn't allow to change, are left as
+/// they are. Numbers have to be checked before.
+fn apply_metadata(
+    tag: &mut dyn AudioTag,
+    metadata: &Metadata,
+    policy: &WritePolicy,
+    clear: &ClearFields,
+) -> anyhow::Result<()> {
+    clear.clear_tag(tag);
+
+    let mut metadata = metadata.clone();
+    clear.clear_derived(&mut metadata);
+
+    let metadata = &policy.filter(
+        &metadata,
+        &metadata_from_tag(tag),
+        tag.is_present(Field::Cover),
+    );
+
+    // Fields are written in the order of `Field`, so that the numbers are
+    // written before their totals
+    for field in Field::value_variants() {
+        match field {
+            Field::Artist => {
+                if let Some(artist) = &metadata.artist {
+                    let mut artists = vec![artist.clone()];
+                    artists.extend(metadata.featured_artists.clone());
+
+                    tag.set_all(Field::Artist, &artists)?;
+                }
+            }
+            Field::Cover => {
+                if let Some(album_cover) = &metadata.album_cover {
+                    tag.set_cover(Cover::from_path(album_cover)?);
+                }
+            }
+            _ => {
+                if let Some(value) = metadata.get(*field) {
+                    tag.set(*field, &value)?;
+                }
             }
         }
+    }
+
+    for field in &metadata.custom_fields {
+        tag.set_custom(&CustomKey::new(&field.key), &field.value);
+    }
+
+    Ok(())
+}
+
+/// Reads the metadata from the tag together with the values of the given
+/// custom fields.
+fn metadata_with_custom(
+    tag: &dyn AudioTag,
+    custom_fields: &[CustomField],
+) -> Metadata {
+    let mut metadata = metadata_from_tag(tag);
+
+    metadata.custom_fields = custom_fields
+        .iter()
+        .filter_map(|x| {
+            Some(CustomField {
+                key: x.key.clone(),
+                value: tag.get_custom(&CustomKey::new(&x.key))?,
+            })
+        })
+        .collect();
+
+    metadata
+}
 
-        bail!("Failed to derive metadata from this filename: {filename_stem}");
+fn metadata_from_tag(tag: &dyn AudioTag) -> Metadata {
+    let mut artists = tag.get_all(Field::Artist).into_iter();
+    let (track_number, track_total) =
+        number_and_total(tag, Field::Track, Field::TrackTotal);
+    let (disc_number, disc_total) =
+        number_and_total(tag, Field::Disc, Field::DiscTotal);
+
+    Metadata {
+        title: tag.get(Field::Title),
+        artist: artists.next(),
+        featured_artists: artists.collect(),
+        album_title: tag.get(Field::Album),
+        album_artist: tag.get(Field::AlbumArtist),
+        album_cover: None,
+        // Date can be written in full, but we need only the year
+        year: tag
+            .get(Field::Year)
+            .and_then(|x| x.get(..4)?.parse().ok())
+            .map(NumberOrToken::Number),
+        track_number,
+        track_total,
+        disc_number,
+        disc_total,
+        genre: tag.get(Field::Genre),
+        composer: tag.get(Field::Composer),
+        comment: tag.get(Field::Comment),
+        grouping: tag.get(Field::Grouping),
+        copyright: tag.get(Field::Copyright),
+        // Tempo can be written with a fraction, like '120.5'
+        bpm: tag
+            .get(Field::Bpm)
+            .and_then(|x| x.split('.').next()?.trim().parse().ok())
+            .map(NumberOrToken::Number),
+        lyrics: tag.get(Field::Lyrics),
+        custom_fields: Vec::new(),
     }
+}
 
-    // fn metadata_from_internet(&self) -> anyhow::Result<Metadata> {
-    //     todo!();
-    // }
+/// Reads the number and the total, that can be written either in separate
+/// fields or together in the first one, like '3/12'.
+fn number_and_total(
+    tag: &dyn AudioTag,
+    number_field: Field,
+    total_field: Field,
+) -> (Option<NumberOrToken>, Option<u32>) {
+    let value = tag.get(number_field).unwrap_or_default();
+    let mut parts = value.split('/').map(|x| x.trim().parse().ok());
+
+    let number = parts.next().flatten();
+    let total = tag
+        .get(total_field)
+        .and_then(|x| x.trim().parse().ok())
+        .or(parts.next().flatten());
+
+    (number.map(NumberOrToken::Number), total)
 }
 
-fn is_supported_type(ext: &str) -> bool {
-    [
-        "mp3", "wav", "flac", "mp4", "m4a", "m4b", "m4p", "m4v", "isom",
-    ]
-    .contains(&ext)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::show::Change;
+
+    fn id3_tag() -> Id3Tag {
+        Id3Tag {
+            tag: id3::Tag::with_version(Version::Id3v24),
+            is_wav: false,
+        }
+    }
+
+    #[test]
+    fn test_id3_round_trip() {
+        let mut tag = id3_tag();
+        let artists = ["Foo".to_string(), "Bar".to_string()];
+
+        tag.set(Field::Title, "Baz").unwrap();
+        tag.set_all(Field::Artist, &artists).unwrap();
+        tag.set(Field::Album, "Quux").unwrap();
+        tag.set(Field::Year, "2023").unwrap();
+        tag.set(Field::Track, "3").unwrap();
+        tag.set(Field::TrackTotal, "12").unwrap();
+        tag.set(Field::Grouping, "Live").unwrap();
+        tag.set(Field::Comment, "Foobar").unwrap();
+        tag.set(Field::Lyrics, "La la la").unwrap();
+
+        // Tag is written and read back, as it happens with the files
+        let mut buffer = Vec::new();
+        tag.tag.write_to(&mut buffer, Version::Id3v24).unwrap();
+        let tag = Id3Tag {
+            tag: id3::Tag::read_from(buffer.as_slice()).unwrap(),
+            is_wav: false,
+        };
+
+        assert_eq!(tag.get(Field::Title).as_deref(), Some("Baz"));
+        assert_eq!(tag.get_all(Field::Artist), artists);
+        assert_eq!(tag.get_all(Field::Album), ["Quux"]);
+        assert_eq!(tag.get(Field::Grouping).as_deref(), Some("Live"));
+        assert_eq!(tag.get(Field::Comment).as_deref(), Some("Foobar"));
+        assert_eq!(tag.get(Field::Lyrics).as_deref(), Some("La la la"));
+
+        let metadata = metadata_from_tag(&tag);
+        assert_eq!(metadata.year.and_then(|x| x.number()), Some(2023));
+        assert_eq!(metadata.track_number.and_then(|x| x.number()), Some(3));
+        assert_eq!(metadata.track_total, Some(12));
+    }
+
+    /// Sets the common fields and checks, that they are read back the same.
+    fn check_fields(tag: &mut dyn AudioTag) {
+        let artists = ["Foo".to_string(), "Bar".to_string()];
+
+        tag.set(Field::Title, "Baz").unwrap();
+        tag.set_all(Field::Artist, &artists).unwrap();
+        tag.set(Field::Album, "Quux").unwrap();
+        tag.set(Field::Year, "2023").unwrap();
+        tag.set(Field::Track, "3").unwrap();
+        tag.set(Field::TrackTotal, "12").unwrap();
+        tag.set(Field::Genre, "Rock").unwrap();
+        tag.set(Field::Comment, "Foobar").unwrap();
+        tag.set(Field::Grouping, "Live").unwrap();
+        tag.set(Field::Copyright, "2023 Foo").unwrap();
+        tag.set(Field::Bpm, "120").unwrap();
+        tag.set(Field::Lyrics, "La la\nla").unwrap();
+
+        assert_eq!(tag.get_all(Field::Artist), artists);
+
+        let metadata = metadata_from_tag(tag);
+        assert_eq!(metadata.title.as_deref(), Some("Baz"));
+        assert_eq!(metadata.artist.as_deref(), Some("Foo"));
+        assert_eq!(metadata.featured_artists, ["Bar"]);
+        assert_eq!(metadata.album_title.as_deref(), Some("Quux"));
+        assert_eq!(metadata.year.and_then(|x| x.number()), Some(2023));
+        assert_eq!(metadata.track_number.and_then(|x| x.number()), Some(3));
+        assert_eq!(metadata.track_total, Some(12));
+        assert_eq!(metadata.genre.as_deref(), Some("Rock"));
+        assert_eq!(metadata.comment.as_deref(), Some("Foobar"));
+        assert_eq!(metadata.grouping.as_deref(), Some("Live"));
+        assert_eq!(metadata.copyright.as_deref(), Some("2023 Foo"));
+        assert_eq!(metadata.bpm.and_then(|x| x.number()), Some(120));
+        assert_eq!(metadata.lyrics.as_deref(), Some("La la\nla"));
+
+        for field in [Field::Title, Field::Bpm, Field::Lyrics] {
+            tag.remove(field);
+            assert_eq!(tag.get(field), None);
+        }
+    }
+
+    #[test]
+    fn test_backends() {
+        check_fields(&mut id3_tag());
+        check_fields(&mut Mp4Tag {
+            tag: mp4ameta::Tag::default(),
+        });
+        check_fields(&mut FlacTag {
+            tag: metaflac::Tag::new(),
+        });
+        check_fields(&mut OggTag {
+            comments: VorbisComments::default(),
+            file_type: FileType::Vorbis,
+        });
+    }
+
+    #[test]
+    fn test_write_policy() {
+        let policy = WritePolicy {
+            policy: Policy::FillMissing,
+            keep: vec![Field::Year, Field::Title],
+            fill_missing: vec![Field::Title],
+            overwrite: vec![Field::Album, Field::Year],
+        };
+
+        // Keep wins over the other lists, which win over the default policy
+        assert_eq!(policy.policy_for(Field::Title), Policy::Keep);
+        assert_eq!(policy.policy_for(Field::Year), Policy::Keep);
+        assert_eq!(policy.policy_for(Field::Album), Policy::Overwrite);
+        assert_eq!(policy.policy_for(Field::Artist), Policy::FillMissing);
+
+        let metadata = Metadata {
+            title: Some("Bar".to_string()),
+            artist: Some("Foo".to_string()),
+            album_title: Some("Baz".to_string()),
+            year: Some(NumberOrToken::Number(2001)),
+            track_number: Some(NumberOrToken::Number(3)),
+            album_cover: Some(PathBuf::from("cover.jpg")),
+            ..Default::default()
+        };
+        let existing = Metadata {
+            artist: Some("Old".to_string()),
+            album_title: Some("Old".to_string()),
+            ..Default::default()
+        };
+
+        let filtered = policy.filter(&metadata, &existing, true);
+        assert_eq!(filtered.title, None);
+        assert!(filtered.year.is_none());
+        assert_eq!(filtered.album_title.as_deref(), Some("Baz"));
+        assert_eq!(filtered.artist, None);
+        assert_eq!(filtered.track_number.and_then(|x| x.number()), Some(3));
+        assert_eq!(filtered.album_cover, None);
+
+        // By default, all the fields are overwritten
+        let filtered =
+            WritePolicy::default().filter(&metadata, &existing, true);
+        assert_eq!(filtered.title.as_deref(), Some("Bar"));
+        assert_eq!(filtered.artist.as_deref(), Some("Foo"));
+        assert_eq!(filtered.album_cover, metadata.album_cover);
+    }
+
+    #[test]
+    fn test_custom_key() {
+        let key = |id3: Id3Key, vorbis: &str, mp4: &str| CustomKey {
+            id3,
+            vorbis: vorbis.to_string(),
+            mp4: mp4.to_string(),
+        };
+        let frame = |x: &str| Id3Key::Frame(x.to_string());
+        let extended = |x: &str| Id3Key::Extended(x.to_string());
+
+        assert_eq!(
+            CustomKey::new("Label"),
+            key(frame("TPUB"), "LABEL", "LABEL")
+        );
+        assert_eq!(
+            CustomKey::new("barcode"),
+            key(extended("BARCODE"), "BARCODE", "BARCODE")
+        );
+        assert_eq!(
+            CustomKey::new("TXXX:Source"),
+            key(extended("Source"), "SOURCE", "Source")
+        );
+        assert_eq!(CustomKey::new("TKEY"), key(frame("TKEY"), "TKEY", "TKEY"));
+        assert_eq!(
+            CustomKey::new("TXXX"),
+            key(extended("TXXX"), "TXXX", "TXXX")
+        );
+        assert_eq!(
+            CustomKey::new("rating"),
+            key(extended("rating"), "RATING", "rating")
+        );
+
+        assert!("TXXX=foo".parse::<CustomField>().is_err());
+        assert!("TXXX:=foo".parse::<CustomField>().is_err());
+        assert!("TXXX:Source=foo".parse::<CustomField>().is_ok());
+        assert!("TCOP=foo".parse::<CustomField>().is_err());
+        assert!("copyright=foo".parse::<CustomField>().is_err());
+        assert!("TXXX:Title=foo".parse::<CustomField>().is_err());
+    }
+
+    #[test]
+    fn test_id3_lyrics_and_bpm() {
+        let mut tag = id3_tag();
+
+        // Lyrics with a description are written by other programs
+        tag.tag.add_frame(id3::frame::Lyrics {
+            lang: "eng".to_string(),
+            description: "Other".to_string(),
+            text: "Foo".to_string(),
+        });
+        assert_eq!(tag.get(Field::Lyrics), None);
+
+        // Tempo can be written with a fraction by other programs
+        tag.tag.set_text("TBPM", "120.5");
+        assert_eq!(
+            metadata_from_tag(&tag).bpm.and_then(|x| x.number()),
+            Some(120)
+        );
+    }
+
+    #[test]
+    fn test_id3_empty_tag() {
+        let tag = id3_tag();
+
+        assert_eq!(tag.get(Field::Title), None);
+        assert!(tag.get_all(Field::Artist).is_empty());
+        assert!(metadata_from_tag(&tag).title.is_none());
+    }
+
+    #[test]
+    fn test_id3_total_without_number() {
+        let mut tag = id3_tag();
+
+        assert!(tag.set(Field::TrackTotal, "12").is_err());
+        assert!(tag.set(Field::DiscTotal, "2").is_err());
+        assert_eq!(tag.get(Field::Track), None);
+        assert_eq!(tag.get(Field::Disc), None);
+
+        tag.set(Field::Track, "3").unwrap();
+        tag.set(Field::TrackTotal, "12").unwrap();
+        assert_eq!(tag.get(Field::Track).as_deref(), Some("3"));
+        assert_eq!(tag.get(Field::TrackTotal).as_deref(), Some("12"));
+    }
+
+    #[test]
+    fn test_id3_clear_number_with_total() {
+        let mut tag = id3_tag();
+        tag.set(Field::Track, "3").unwrap();
+        tag.set(Field::TrackTotal, "12").unwrap();
+
+        let old = metadata_from_tag(&tag);
+        let clear = ClearFields {
+            clear: vec![Field::Track],
+            clear_all: false,
+        };
+        let metadata = Metadata::default();
+        apply_metadata(&mut tag, &metadata, &WritePolicy::default(), &clear)
+            .unwrap();
+
+        // Total is stored in the same frame, so it is removed together with
+        // the number, and the difference shows it
+        let changes = diff(&old, None, &metadata_from_tag(&tag), None);
+        let change = |name: &str| {
+            changes
+                .iter()
+                .find(|x| x.name == name)
+                .unwrap()
+                .change
+                .clone()
+        };
+        assert_eq!(change("Track"), Change::Removed);
+        assert_eq!(change("Track total"), Change::Removed);
+        assert_eq!(change("Title"), Change::Unchanged);
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let mut tag = id3_tag();
+        tag.set(Field::Title, "Old").unwrap();
+
+        let old = metadata_from_tag(&tag);
+        let metadata = Metadata {
+            title: Some("New".to_string()),
+            genre: Some("Rock".to_string()),
+            ..Default::default()
+        };
+        let policy = WritePolicy {
+            keep: vec![Field::Title],
+            ..Default::default()
+        };
+        apply_metadata(&mut tag, &metadata, &policy, &ClearFields::default())
+            .unwrap();
+
+        let changes = diff(&old, None, &metadata_from_tag(&tag), None);
+        let change = |name: &str| {
+            changes
+                .iter()
+                .find(|x| x.name == name)
+                .unwrap()
+                .change
+                .clone()
+        };
+        assert_eq!(change("Title"), Change::Unchanged);
+        assert_eq!(change("Genre"), Change::Set("Rock".to_string()));
+    }
 }
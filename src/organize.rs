@@ -0,0 +1,231 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::metadata::{AudioFile, Metadata};
+use crate::parse::ParsePattern;
+use crate::rename::{check_collisions, sanitize_filename, track_width, Rename};
+
+pub const DEFAULT_TEMPLATE: &str = "{A}/{m} ({y})/{d} - {t}.{ext}";
+
+/// The way files are put into the library.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Transfer {
+    #[default]
+    Move,
+    Copy,
+    Hardlink,
+}
+
+/// Template of the path of the file in the library, relative to its root.
+/// Components of the path are separated with '/' and each of them is a
+/// pattern, that is rendered in the same way as by `fme rename`. The
+/// filename may end with '.{ext}', which is replaced with the extension of
+/// the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    components: Vec<ParsePattern>,
+    extension: bool,
+}
+
+impl PathTemplate {
+    /// Characters, that are not allowed in filenames, are replaced with
+    /// underscores in each component. Album artist falls back to the artist,
+    /// so that compilations and albums without it are not mixed together.
+    pub fn render(
+        &self,
+        metadata: &Metadata,
+        ext: &str,
+        track_width: usize,
+    ) -> anyhow::Result<PathBuf> {
+        let mut metadata = metadata.clone();
+        metadata.album_artist =
+            metadata.album_artist.or_else(|| metadata.artist.clone());
+
+        let mut names = Vec::new();
+
+        for component in &self.components {
+            let name = component.render(&metadata, track_width)?;
+            let name = sanitize_filename(&name);
+
+            if name.is_empty() {
+                bail!("Template produced an empty path component");
+            }
+
+            names.push(name);
+        }
+
+        // Extension is added after the filename is sanitized, so that the
+        // trailing dots of the name are removed in the same way as when the
+        // file is renamed
+        if let Some(name) = names.last_mut().filter(|_| self.extension) {
+            if !ext.is_empty() {
+                name.push('.');
+                name.push_str(ext);
+            }
+        }
+
+        Ok(names.iter().collect())
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let (string, extension) = match string.strip_suffix(".{ext}") {
+            Some(x) => (x, true),
+            None => (string, false),
+        };
+
+        if string.contains("{ext}") {
+            bail!(
+                "Token '{{ext}}' can be used only at the end of the template"
+            );
+        }
+
+        let mut components = Vec::new();
+
+        for component in string.split('/') {
+            if component.is_empty() {
+                bail!("Template can't contain empty path components");
+            }
+
+            components.push(component.parse()?);
+        }
+
+        Ok(Self {
+            components,
+            extension,
+        })
+    }
+}
+
+/// Builds the paths of the files under the library root from the template.
+/// Files, that would overwrite existing ones, get errors instead.
+pub fn plan_organize(
+    files: &[AudioFile],
+    template: &PathTemplate,
+    root: &Path,
+) -> Vec<anyhow::Result<Rename>> {
+    let metadata: Vec<_> = files.iter().map(AudioFile::read_metadata).collect();
+    let track_width = track_width(&metadata);
+
+    let mut plan: Vec<anyhow::Result<Rename>> = files
+        .iter()
+        .zip(metadata)
+        .map(|(file, metadata)| {
+            let metadata = metadata?;
+            let from = file.as_path().to_path_buf();
+            let ext = from
+                .extension()
+                .map(|x| x.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+
+            let path = template.render(&metadata, &ext, track_width).context(
+                format!(
+                    "Can't build a path in the library for the file '{}'",
+                    file.path()
+                ),
+            )?;
+
+            Ok(Rename {
+                from,
+                to: root.join(path),
+            })
+        })
+        .collect();
+
+    check_collisions(&mut plan);
+
+    plan
+}
+
+/// Moves (or copies, or hardlinks) the file to its place in the library.
+pub fn transfer_file(
+    rename: &Rename,
+    transfer: Transfer,
+) -> anyhow::Result<()> {
+    let from = &rename.from;
+    let to = &rename.to;
+    let context = format!(
+        "Failed to put '{}' to '{}'",
+        from.to_string_lossy(),
+        to.to_string_lossy()
+    );
+
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).context(context.clone())?;
+    }
+
+    match transfer {
+        Transfer::Move => {
+            // Files can't be renamed across filesystems, so in this case we
+            // copy the file and remove the original one
+            if fs::rename(from, to).is_err() {
+                fs::copy(from, to).context(context.clone())?;
+                fs::remove_file(from).context(context)?;
+            }
+        }
+
+        Transfer::Copy => {
+            fs::copy(from, to).context(context)?;
+        }
+
+        Transfer::Hardlink => fs::hard_link(from, to).context(context)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::NumberOrToken;
+
+    #[test]
+    fn test_render_template() {
+        let template = PathTemplate::from_str(DEFAULT_TEMPLATE).unwrap();
+        let mut metadata = Metadata {
+            genre: Some("Hard rock".to_string()),
+            artist: Some("AC/DC".to_string()),
+            title: Some("T.N.T.".to_string()),
+            album_title: Some("High Voltage".to_string()),
+            year: Some(NumberOrToken::Number(1976)),
+            track_number: Some(NumberOrToken::Number(3)),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            template.render(&metadata, "mp3", 2).unwrap(),
+            PathBuf::from("AC_DC/High Voltage (1976)/03 - T.N.T.mp3")
+        );
+
+        // Optional sections and transforms work in the same way as in
+        // `fme rename`
+        let template =
+            PathTemplate::from_str("{g|lower}/{m}[ ({D})]/{d}.{ext}").unwrap();
+        assert_eq!(
+            template.render(&metadata, "flac", 3).unwrap(),
+            PathBuf::from("hard rock/High Voltage/003.flac")
+        );
+
+        metadata.year = None;
+        assert!(PathTemplate::from_str(DEFAULT_TEMPLATE)
+            .unwrap()
+            .render(&metadata, "mp3", 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_template_from_str() {
+        assert!(PathTemplate::from_str("{a}/{t}.{ext}").is_ok());
+        assert!(PathTemplate::from_str("/{a}/{t}").is_err());
+        assert!(PathTemplate::from_str("{a}/{x}").is_err());
+        assert!(PathTemplate::from_str("{a:02}").is_err());
+        assert!(PathTemplate::from_str("{a}/{t").is_err());
+        assert!(PathTemplate::from_str("{a}/{ext}/{t}").is_err());
+    }
+}
@@ -1,10 +1,17 @@
 use anyhow::{bail, Context};
-use regex::Regex;
-use std::str::FromStr;
+use regex::{Captures, Regex};
+use std::fmt;
+use std::str::{Chars, FromStr};
 
-use crate::metadata::Metadata;
+use crate::metadata::{Metadata, NumberOrToken};
+use crate::transform::{apply_transforms, split_transforms, Transform};
 
-const TOKEN_VALUES: [&str; 5] = ["{a}", "{t}", "{d}", "{m}", "{y}"];
+// Characters, that have special meaning in patterns and have to be escaped
+// with '\' to be used literally
+const SPECIAL_CHARS: [char; 8] = ['\\', '{', '}', '[', ']', '(', ')', '|'];
+
+// Numbers in the tags fit into u32, which has at most 10 digits
+const MAX_WIDTH: usize = 10;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ParsePattern {
@@ -18,18 +25,12 @@ impl ParsePattern {
 
     pub fn default_patterns() -> Vec<Self> {
         let patterns = [
-            "{d} {a} - {t}",
-            "{d} {a} — {t}",
-            "{d}. {a} - {t}",
-            "{d}. {a} — {t}",
-            "{a} - {d} {t}",
-            "{a} — {d} {t}",
-            "{a} - {d}. {t}",
-            "{a} — {d}. {t}",
-            "{a} - {t}",
-            "{a} — {t}",
-            "{d} {t}",
-            "{d}. {t}",
+            "{a}/{y} - {m}/{d}( -|.) {t}",
+            "{a}/{m} ({y})/{d}( -|.) {t}",
+            "{d}[.] {a} (-|—|–) {t}",
+            "{a} (-|—|–) {d}[.] {t}",
+            "{a} (-|—|–) {t}",
+            "{d}[.] {t}",
             "{t}",
         ];
 
@@ -39,97 +40,431 @@ impl ParsePattern {
             .collect()
     }
 
-    pub fn try_pattern(&self, input: &str) -> anyhow::Result<Metadata> {
-        let mut metadata = Metadata::default();
-
-        let mut regex_str = self
+    /// Number of path components, that the pattern describes. Patterns
+    /// without '/' describe only the filename.
+    pub fn depth(&self) -> usize {
+        // Separators are not allowed in optional sections and alternatives,
+        // so they can only be in the text on the top level
+        let separators: usize = self
             .items
             .iter()
             .map(|x| match x {
-                ItemPattern::Text(s) => regex::escape(s),
-                ItemPattern::Token(t) => t.token_to_regex_repr(),
+                ItemPattern::Text(s) => s.matches('/').count(),
+                _ => 0,
             })
-            .collect::<String>();
+            .sum();
 
-        regex_str.push('$');
-        regex_str.insert(0, '^');
+        separators + 1
+    }
 
-        let regex = Regex::new(&regex_str).unwrap();
+    pub fn try_pattern(&self, input: &str) -> anyhow::Result<Metadata> {
+        let mut metadata = Metadata::default();
+
+        let regex_str = format!("^{}$", items_to_regex(&self.items));
+
+        let regex = Regex::new(&regex_str)?;
         let groups = regex
             .captures(input)
             .context("Failed to parse given string using this pattern")?;
 
-        let tokens: Vec<_> = self
-            .items
-            .iter()
-            .filter_map(|x| {
-                if let ItemPattern::Token(token) = x {
-                    Some(token)
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let mut tokens = Vec::new();
+        collect_tokens(&self.items, &mut tokens);
 
+        // Tokens in optional sections, that were skipped, don't participate
+        // in the match
         for (i, token) in tokens.iter().enumerate() {
-            let value = groups.get(i + 1).unwrap().as_str();
-            token.apply_token(value, &mut metadata)?;
+            if let Some(value) = groups.get(i + 1) {
+                token.apply_token(value.as_str(), &mut metadata)?;
+            }
         }
 
         Ok(metadata)
     }
+
+    /// Does the opposite to `try_pattern`: builds a string from the pattern,
+    /// putting values of the fields in place of the tokens. Track number is
+    /// padded with zeros up to `track_width` digits. Optional sections are
+    /// left out, if some of their tokens have no value, and the first of the
+    /// alternatives is always used.
+    pub fn render(
+        &self,
+        metadata: &Metadata,
+        track_width: usize,
+    ) -> anyhow::Result<String> {
+        render_items(&self.items, metadata, track_width)
+    }
+}
+
+fn items_to_regex(items: &[ItemPattern]) -> String {
+    items
+        .iter()
+        .map(|x| match x {
+            ItemPattern::Text(s) => regex::escape(s),
+            ItemPattern::Token(t) => t.to_regex(),
+            ItemPattern::Optional(items) => {
+                format!("(?:{})?", items_to_regex(items))
+            }
+            ItemPattern::Alternatives(alternatives) => {
+                let alternatives: Vec<_> =
+                    alternatives.iter().map(|x| regex::escape(x)).collect();
+
+                format!("(?:{})", alternatives.join("|"))
+            }
+        })
+        .collect()
+}
+
+fn collect_tokens<'a>(
+    items: &'a [ItemPattern],
+    tokens: &mut Vec<&'a TokenPattern>,
+) {
+    for item in items {
+        match item {
+            ItemPattern::Token(token) => tokens.push(token),
+            ItemPattern::Optional(items) => collect_tokens(items, tokens),
+            ItemPattern::Text(_) | ItemPattern::Alternatives(_) => {}
+        }
+    }
+}
+
+fn render_items(
+    items: &[ItemPattern],
+    metadata: &Metadata,
+    track_width: usize,
+) -> anyhow::Result<String> {
+    let mut output = String::new();
+
+    for item in items {
+        match item {
+            ItemPattern::Text(s) => output.push_str(s),
+            ItemPattern::Token(token) => {
+                output.push_str(&token.render(metadata, track_width)?);
+            }
+            ItemPattern::Optional(items) => {
+                if let Ok(x) = render_items(items, metadata, track_width) {
+                    output.push_str(&x);
+                }
+            }
+            ItemPattern::Alternatives(alternatives) => {
+                output.push_str(&alternatives[0]);
+            }
+        }
+    }
+
+    Ok(output)
 }
 
 impl FromStr for ParsePattern {
     type Err = anyhow::Error;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        let mut split = vec![string];
+        let items = parse_items(&mut string.chars(), false)?;
+
+        Ok(ParsePattern::new(items))
+    }
+}
+
+/// Parses the pattern up to its end or, if it is an optional section, up
+/// to the closing ']'.
+fn parse_items(
+    chars: &mut Chars,
+    is_optional: bool,
+) -> anyhow::Result<Vec<ItemPattern>> {
+    let mut items = Vec::new();
+    let mut text = String::new();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let c = chars.next().context("Pattern can't end with '\\'")?;
+                text.push(c);
+            }
+
+            '{' => {
+                push_text(&mut items, &mut text);
+
+                let mut contents = String::new();
 
-        for token in TOKEN_VALUES {
-            split = split.iter().fold(Vec::new(), |mut acc, x| {
-                acc.append(&mut keep_split(x, token));
-                acc
-            });
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => contents.push(c),
+                        None => {
+                            bail!("There is an unclosed '{{' in the pattern")
+                        }
+                    }
+                }
+
+                items.push(ItemPattern::Token(contents.parse()?));
+            }
+
+            '[' => {
+                push_text(&mut items, &mut text);
+                items.push(ItemPattern::Optional(parse_items(chars, true)?));
+            }
+
+            ']' if is_optional => {
+                push_text(&mut items, &mut text);
+                return Ok(items);
+            }
+
+            ']' => bail!("There is an unmatched ']' in the pattern"),
+
+            '(' => match parse_alternatives(chars)? {
+                Some(alternatives) => {
+                    push_text(&mut items, &mut text);
+                    items.push(ItemPattern::Alternatives(alternatives));
+                }
+                None => text.push(c),
+            },
+
+            '/' if is_optional => {
+                bail!("Optional sections can't contain '/'")
+            }
+
+            _ => text.push(c),
         }
+    }
 
-        let mut items = Vec::new();
+    if is_optional {
+        bail!("There is an unclosed '[' in the pattern");
+    }
+
+    push_text(&mut items, &mut text);
 
-        for s in split {
-            match ItemPattern::from_str(s) {
-                Ok(s) => items.push(s),
-                Err(e) => bail!("{e}"),
+    Ok(items)
+}
+
+fn push_text(items: &mut Vec<ItemPattern>, text: &mut String) {
+    if !text.is_empty() {
+        items.push(ItemPattern::Text(std::mem::take(text)));
+    }
+}
+
+/// Parses alternatives of the text, like '(-|—)', after the opening '('.
+/// Parentheses, that contain tokens or no '|', are not alternatives, but
+/// just text (e.g. '{m} ({y})'), so `None` is returned in this case and
+/// nothing is consumed.
+fn parse_alternatives(
+    chars: &mut Chars,
+) -> anyhow::Result<Option<Vec<String>>> {
+    let mut lookahead = chars.clone();
+    let mut alternatives = vec![String::new()];
+
+    loop {
+        match lookahead.next() {
+            None | Some('{' | '(' | '[' | ']') => return Ok(None),
+            Some(')') => break,
+            Some('|') => alternatives.push(String::new()),
+            Some('\\') => {
+                let c =
+                    lookahead.next().context("Pattern can't end with '\\'")?;
+                alternatives.last_mut().unwrap().push(c);
             }
+            Some(c) => alternatives.last_mut().unwrap().push(c),
         }
+    }
 
-        Ok(ParsePattern::new(items))
+    if alternatives.len() < 2 {
+        return Ok(None);
+    }
+
+    if alternatives.iter().any(|x| x.contains('/')) {
+        bail!("Alternatives can't contain '/'");
     }
+
+    *chars = lookahead;
+
+    Ok(Some(alternatives))
 }
 
-fn keep_split<'a>(input: &'a str, token: &'a str) -> Vec<&'a str> {
-    itertools::intersperse(input.split(token), token)
-        .filter(|x| !x.is_empty())
-        .collect()
+impl fmt::Display for ParsePattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_items(&self.items, f)
+    }
+}
+
+fn fmt_items(items: &[ItemPattern], f: &mut fmt::Formatter) -> fmt::Result {
+    let escape = |s: &str| {
+        s.chars().fold(String::new(), |mut acc, c| {
+            if SPECIAL_CHARS.contains(&c) {
+                acc.push('\\');
+            }
+            acc.push(c);
+            acc
+        })
+    };
+
+    for item in items {
+        match item {
+            ItemPattern::Text(s) => write!(f, "{}", escape(s))?,
+            ItemPattern::Token(token) => write!(f, "{token}")?,
+            ItemPattern::Optional(items) => {
+                write!(f, "[")?;
+                fmt_items(items, f)?;
+                write!(f, "]")?;
+            }
+            ItemPattern::Alternatives(alternatives) => {
+                let alternatives: Vec<_> =
+                    alternatives.iter().map(|x| escape(x)).collect();
+
+                write!(f, "({})", alternatives.join("|"))?;
+            }
+        }
+    }
+
+    Ok(())
 }
 
+/// Puts values of the named groups of the regex, that are called after the
+/// fields (e.g. `(?P<artist>...)` or `(?P<track>...)`), to the corresponding
+/// fields of the metadata. Other groups are ignored.
+pub fn metadata_from_named_groups(
+    regex: &Regex,
+    captures: &Captures,
+) -> anyhow::Result<Metadata> {
+    let mut metadata = Metadata::default();
+
+    for name in regex.capture_names().flatten() {
+        let Some(token) = Token::from_group_name(name) else {
+            continue;
+        };
+
+        if let Some(value) = captures.name(name) {
+            token
+                .apply_token(value.as_str(), &mut metadata)
+                .context(format!("Invalid value of the group '{name}'"))?;
+        }
+    }
+
+    Ok(metadata)
+}
 
 #[derive(Debug, Clone, PartialEq)]
 enum ItemPattern {
     Text(String),
-    Token(Token),
+    Token(TokenPattern),
+    /// Section, that may be absent, like '[{d}. ]'
+    Optional(Vec<ItemPattern>),
+    /// One of the given texts, like '(-|—)'
+    Alternatives(Vec<String>),
 }
 
-impl FromStr for ItemPattern {
+/// Token with its modifiers, like '{d:02}' or '{t|trim|title}'. Width of
+/// a number is the exact number of digits, when the pattern is matched, and
+/// the number is padded with zeros up to it, when the pattern is rendered.
+/// Transforms are applied to the matched value before it is written and to
+/// the rendered value.
+#[derive(Debug, Clone, PartialEq)]
+struct TokenPattern {
+    token: Token,
+    width: Option<usize>,
+    transforms: Vec<Transform>,
+}
+
+impl From<Token> for TokenPattern {
+    fn from(token: Token) -> Self {
+        Self {
+            token,
+            width: None,
+            transforms: Vec::new(),
+        }
+    }
+}
+
+impl TokenPattern {
+    fn to_regex(&self) -> String {
+        match self.width {
+            Some(width) => format!("([0-9]{{{width}}})"),
+            None => self.token.token_to_regex_repr(),
+        }
+    }
+
+    fn apply_token(
+        &self,
+        value: &str,
+        metadata: &mut Metadata,
+    ) -> anyhow::Result<()> {
+        let value = apply_transforms(&self.transforms, value);
+
+        self.token.apply_token(&value, metadata)
+    }
+
+    fn render(
+        &self,
+        metadata: &Metadata,
+        track_width: usize,
+    ) -> anyhow::Result<String> {
+        let width = match self.token {
+            Token::Track => self.width.unwrap_or(track_width),
+            _ => self.width.unwrap_or_default(),
+        };
+        let value = self.token.render(metadata, width)?;
+
+        Ok(apply_transforms(&self.transforms, &value))
+    }
+}
+
+impl FromStr for TokenPattern {
     type Err = anyhow::Error;
 
     fn from_str(string: &str) -> Result<Self, Self::Err> {
-        match Token::from_str(string) {
-            Ok(x) => Ok(ItemPattern::Token(x)),
-            Err(_) => Ok(ItemPattern::Text(string.to_string())),
-        }
+        let (name, transforms) = split_transforms(string)?;
+        let (name, width) = match name.split_once(':') {
+            Some((name, width)) => (name, Some(width)),
+            None => (name, None),
+        };
+
+        let token =
+            Token::from_str(&format!("{{{name}}}")).context(format!(
+            "Unknown token '{{{name}}}' in the pattern, use '\\{{' to match \
+            a literal brace"
+        ))?;
+
+        let width = match width {
+            Some(_) if !token.is_number() => {
+                bail!("Width can be specified only for numbers")
+            }
+            Some(width) => {
+                let width = width
+                    .parse()
+                    .ok()
+                    .filter(|x| (1..=MAX_WIDTH).contains(x))
+                    .context(format!(
+                        "Invalid width '{width}' in the pattern, it has to be \
+                        from 1 to {MAX_WIDTH}"
+                    ))?;
+
+                Some(width)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            token,
+            width,
+            transforms,
+        })
     }
 }
 
+impl fmt::Display for TokenPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.token.as_str().trim_matches(|c| c == '{' || c == '}');
+
+        write!(f, "{{{name}")?;
+
+        if let Some(width) = self.width {
+            write!(f, ":{width:02}")?;
+        }
+
+        for transform in &self.transforms {
+            write!(f, "|{transform}")?;
+        }
+
+        write!(f, "}}")
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 enum Token {
@@ -138,6 +473,17 @@ enum Token {
     Album,
     Year,
     Track,
+    Disc,
+    AlbumArtist,
+    Genre,
+    Composer,
+    Comment,
+    Grouping,
+    Copyright,
+    Bpm,
+    Lyrics,
+    /// Matches anything, but the matched value is thrown away
+    Ignore,
 }
 
 impl Token {
@@ -147,13 +493,27 @@ impl Token {
         let regex_num = r"([0-9]+)";
 
         let regex_repr = match self {
-            Token::Artist | Token::Title | Token::Album => regex_text,
-            Token::Year | Token::Track => regex_num,
+            Token::Artist
+            | Token::Title
+            | Token::Album
+            | Token::AlbumArtist
+            | Token::Genre
+            | Token::Composer
+            | Token::Comment
+            | Token::Grouping
+            | Token::Copyright
+            | Token::Lyrics
+            | Token::Ignore => regex_text,
+            Token::Year | Token::Track | Token::Disc | Token::Bpm => regex_num,
         };
 
         regex_repr.to_string()
     }
 
+    fn is_number(&self) -> bool {
+        matches!(self, Token::Year | Token::Track | Token::Disc | Token::Bpm)
+    }
+
     fn apply_token(
         &self,
         value: &str,
@@ -167,10 +527,99 @@ impl Token {
             Token::Album => metadata.album_title = Some(value.parse()?),
             Token::Year => metadata.year = Some(value.parse()?),
             Token::Track => metadata.track_number = Some(value.parse()?),
+            Token::Disc => metadata.disc_number = Some(value.parse()?),
+            Token::AlbumArtist => metadata.album_artist = Some(value.parse()?),
+            Token::Genre => metadata.genre = Some(value.parse()?),
+            Token::Composer => metadata.composer = Some(value.parse()?),
+            Token::Comment => metadata.comment = Some(value.parse()?),
+            Token::Grouping => metadata.grouping = Some(value.parse()?),
+            Token::Copyright => metadata.copyright = Some(value.parse()?),
+            Token::Bpm => metadata.bpm = Some(value.parse()?),
+            Token::Lyrics => metadata.lyrics = Some(value.parse()?),
+            Token::Ignore => {}
         }
 
         Ok(())
     }
+
+    /// Numbers are padded with zeros up to `width` digits.
+    fn render(
+        &self,
+        metadata: &Metadata,
+        width: usize,
+    ) -> anyhow::Result<String> {
+        let number = |x: &Option<NumberOrToken>| {
+            x.as_ref()
+                .and_then(NumberOrToken::number)
+                .map(|x| format!("{x:0width$}"))
+        };
+
+        let value = match self {
+            Token::Artist => metadata.artist.clone(),
+            Token::Title => metadata.title.clone(),
+            Token::Album => metadata.album_title.clone(),
+            Token::Year => number(&metadata.year),
+            Token::Track => number(&metadata.track_number),
+            Token::Disc => number(&metadata.disc_number),
+            Token::AlbumArtist => metadata.album_artist.clone(),
+            Token::Genre => metadata.genre.clone(),
+            Token::Composer => metadata.composer.clone(),
+            Token::Comment => metadata.comment.clone(),
+            Token::Grouping => metadata.grouping.clone(),
+            Token::Copyright => metadata.copyright.clone(),
+            Token::Bpm => number(&metadata.bpm),
+            Token::Lyrics => metadata.lyrics.clone(),
+            Token::Ignore => {
+                bail!("Token '{{_}}' can't be used to build a string")
+            }
+        };
+
+        value.with_context(|| {
+            format!("There is no value for the token '{}'", self.as_str())
+        })
+    }
+
+    fn from_group_name(name: &str) -> Option<Self> {
+        let token = match name {
+            "artist" => Token::Artist,
+            "title" => Token::Title,
+            "album" => Token::Album,
+            "year" => Token::Year,
+            "track" => Token::Track,
+            "disc" => Token::Disc,
+            "album_artist" => Token::AlbumArtist,
+            "genre" => Token::Genre,
+            "composer" => Token::Composer,
+            "comment" => Token::Comment,
+            "grouping" => Token::Grouping,
+            "copyright" => Token::Copyright,
+            "bpm" => Token::Bpm,
+            "lyrics" => Token::Lyrics,
+            _ => return None,
+        };
+
+        Some(token)
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Token::Artist => "{a}",
+            Token::Title => "{t}",
+            Token::Album => "{m}",
+            Token::Year => "{y}",
+            Token::Track => "{d}",
+            Token::Disc => "{D}",
+            Token::AlbumArtist => "{A}",
+            Token::Genre => "{g}",
+            Token::Composer => "{c}",
+            Token::Comment => "{C}",
+            Token::Grouping => "{G}",
+            Token::Copyright => "{r}",
+            Token::Bpm => "{b}",
+            Token::Lyrics => "{l}",
+            Token::Ignore => "{_}",
+        }
+    }
 }
 
 impl FromStr for Token {
@@ -183,6 +632,16 @@ impl FromStr for Token {
             "{m}" => Token::Album,
             "{y}" => Token::Year,
             "{d}" => Token::Track,
+            "{D}" => Token::Disc,
+            "{A}" => Token::AlbumArtist,
+            "{g}" => Token::Genre,
+            "{c}" => Token::Composer,
+            "{C}" => Token::Comment,
+            "{G}" => Token::Grouping,
+            "{r}" => Token::Copyright,
+            "{b}" => Token::Bpm,
+            "{l}" => Token::Lyrics,
+            "{_}" => Token::Ignore,
             _ => bail!("Unknown token"),
         };
 
@@ -200,11 +659,11 @@ mod tests {
             ParsePattern::from_str("{d} {a} - {t}").unwrap(),
             ParsePattern::new(
                 [
-                    ItemPattern::Token(Token::Track),
+                    ItemPattern::Token(Token::Track.into()),
                     ItemPattern::Text(" ".to_string()),
-                    ItemPattern::Token(Token::Artist),
+                    ItemPattern::Token(Token::Artist.into()),
                     ItemPattern::Text(" - ".to_string()),
-                    ItemPattern::Token(Token::Title)
+                    ItemPattern::Token(Token::Title.into())
                 ]
                 .to_vec()
             )
@@ -214,11 +673,11 @@ mod tests {
             ParsePattern::from_str("{d}. {a} — {t}").unwrap(),
             ParsePattern::new(
                 [
-                    ItemPattern::Token(Token::Track),
+                    ItemPattern::Token(Token::Track.into()),
                     ItemPattern::Text(". ".to_string()),
-                    ItemPattern::Token(Token::Artist),
+                    ItemPattern::Token(Token::Artist.into()),
                     ItemPattern::Text(" — ".to_string()),
-                    ItemPattern::Token(Token::Title)
+                    ItemPattern::Token(Token::Title.into())
                 ]
                 .to_vec()
             )
@@ -228,10 +687,10 @@ mod tests {
             ParsePattern::from_str("{a}{a} - {t}").unwrap(),
             ParsePattern::new(
                 [
-                    ItemPattern::Token(Token::Artist),
-                    ItemPattern::Token(Token::Artist),
+                    ItemPattern::Token(Token::Artist.into()),
+                    ItemPattern::Token(Token::Artist.into()),
                     ItemPattern::Text(" - ".to_string()),
-                    ItemPattern::Token(Token::Title)
+                    ItemPattern::Token(Token::Title.into())
                 ]
                 .to_vec()
             )
@@ -239,8 +698,62 @@ mod tests {
 
         assert_eq!(
             ParsePattern::from_str("{t}").unwrap(),
-            ParsePattern::new([ItemPattern::Token(Token::Title)].to_vec())
+            ParsePattern::new(
+                [ItemPattern::Token(Token::Title.into())].to_vec()
+            )
+        );
+
+        assert_eq!(
+            ParsePattern::from_str(r"[{d}[.] ]{a} (-|—) {m} ({y}) \[x\]")
+                .unwrap(),
+            ParsePattern::new(
+                [
+                    ItemPattern::Optional(
+                        [
+                            ItemPattern::Token(Token::Track.into()),
+                            ItemPattern::Optional(
+                                [ItemPattern::Text(".".to_string())].to_vec()
+                            ),
+                            ItemPattern::Text(" ".to_string()),
+                        ]
+                        .to_vec()
+                    ),
+                    ItemPattern::Token(Token::Artist.into()),
+                    ItemPattern::Text(" ".to_string()),
+                    ItemPattern::Alternatives(
+                        ["-".to_string(), "—".to_string()].to_vec()
+                    ),
+                    ItemPattern::Text(" ".to_string()),
+                    ItemPattern::Token(Token::Album.into()),
+                    ItemPattern::Text(" (".to_string()),
+                    ItemPattern::Token(Token::Year.into()),
+                    ItemPattern::Text(") [x]".to_string()),
+                ]
+                .to_vec()
+            )
         );
+
+        assert!(ParsePattern::from_str("[{d} {t}").is_err());
+        assert!(ParsePattern::from_str("{d}] {t}").is_err());
+        assert!(ParsePattern::from_str("{x} {t}").is_err());
+        assert!(ParsePattern::from_str("{d} {t").is_err());
+    }
+
+    #[test]
+    fn test_optional_and_alternatives() {
+        let pattern = ParsePattern::from_str("[{d}[.] ]{a} (-|—) {t}").unwrap();
+
+        for input in ["12. Foo - Bar", "12 Foo — Bar", "Foo - Bar"] {
+            let metadata = pattern.try_pattern(input).unwrap();
+            assert_eq!(metadata.artist.unwrap(), "Foo");
+            assert_eq!(metadata.title.unwrap(), "Bar");
+        }
+
+        assert!(pattern.try_pattern("Foo – Bar").is_err());
+
+        let metadata = pattern.try_pattern("Foo - Bar").unwrap();
+        assert_eq!(pattern.render(&metadata, 2).unwrap(), "Foo - Bar");
+        assert_eq!(pattern.to_string(), "[{d}[.] ]{a} (-|—) {t}");
     }
 
     #[test]
@@ -261,5 +774,81 @@ mod tests {
         let input2 = "12. Foo - Bar";
         // println!("{:?}", pattern2.try_pattern(input2));
         assert!(pattern2.try_pattern(input2).is_ok());
+
+        let pattern3 =
+            ParsePattern::from_str("{a}/{y} - {m}/{d} - {t}").unwrap();
+        let input3 = "Foo/2023 - Quuz/01 - Bar";
+        assert_eq!(pattern3.depth(), 3);
+        assert!(pattern3.try_pattern(input3).is_ok());
+        assert!(pattern3.try_pattern("2023 - Quuz/01 - Bar").is_err());
+
+        let pattern4 =
+            ParsePattern::from_str(r"{A} - {m} CD{D} - {d} {t} \[{_}\]")
+                .unwrap();
+        let metadata = pattern4
+            .try_pattern("Various - Hits CD2 - 03 Bar [dQw4w9WgXcQ]")
+            .unwrap();
+        assert_eq!(metadata.album_artist.unwrap(), "Various");
+        assert_eq!(metadata.disc_number.and_then(|x| x.number()), Some(2));
+        assert_eq!(metadata.title.unwrap(), "Bar");
+        assert!(metadata.comment.is_none());
+    }
+
+    #[test]
+    fn test_named_groups() {
+        let regex =
+            Regex::new(r"(?P<track>\d+)\. (?P<artist>.+) - (?P<title>.+)")
+                .unwrap();
+        let captures = regex.captures("12. Foo - Bar").unwrap();
+        let metadata = metadata_from_named_groups(&regex, &captures).unwrap();
+
+        assert_eq!(metadata.artist.unwrap(), "Foo");
+        assert_eq!(metadata.title.unwrap(), "Bar");
+        assert_eq!(metadata.track_number.and_then(|x| x.number()), Some(12));
+
+        let regex = Regex::new(r"\[(?P<track>[^\]]+)\] (?P<title>.+)").unwrap();
+
+        for filename in ["[03/12] Bar", "[3 of 12] Bar"] {
+            let captures = regex.captures(filename).unwrap();
+            let mut metadata =
+                metadata_from_named_groups(&regex, &captures).unwrap();
+            metadata.split_totals();
+
+            assert_eq!(metadata.track_number.and_then(|x| x.number()), Some(3));
+            assert_eq!(metadata.track_total, Some(12));
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let pattern = ParsePattern::from_str("{d}. {a} - {t}").unwrap();
+        let mut metadata = ParsePattern::from_str("{a} - {t}")
+            .unwrap()
+            .try_pattern("Foo - Bar")
+            .unwrap();
+
+        assert!(pattern.render(&metadata, 2).is_err());
+
+        metadata.track_number = Some(NumberOrToken::Number(7));
+        assert_eq!(pattern.render(&metadata, 2).unwrap(), "07. Foo - Bar");
+        assert_eq!(pattern.render(&metadata, 3).unwrap(), "007. Foo - Bar");
+
+        let pattern =
+            ParsePattern::from_str("{d:02}{a|lower} - {t|replace(_, )}")
+                .unwrap();
+        let metadata = pattern.try_pattern("0712FOO - Bar_Baz").unwrap();
+        assert_eq!(
+            metadata
+                .track_number
+                .as_ref()
+                .and_then(NumberOrToken::number),
+            Some(7)
+        );
+        assert_eq!(metadata.artist.as_deref(), Some("12foo"));
+        assert_eq!(metadata.title.as_deref(), Some("Bar Baz"));
+        assert_eq!(pattern.render(&metadata, 3).unwrap(), "0712foo - Bar Baz");
+        assert!(ParsePattern::from_str("{a:02}").is_err());
+        assert!(ParsePattern::from_str("{d:0}").is_err());
+        assert!(ParsePattern::from_str("{d:100000000}{t}").is_err());
     }
 }
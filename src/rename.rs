@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::error::Error;
+use crate::metadata::{AudioFile, Metadata};
+use crate::parse::ParsePattern;
+
+// Characters, that are not allowed in filenames on at least one of the
+// common filesystems (FAT and NTFS being the most restrictive ones)
+const FORBIDDEN_CHARS: [char; 9] =
+    ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Replaces characters, that can't be used in filenames, with underscores.
+pub fn sanitize_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || FORBIDDEN_CHARS.contains(&c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // Windows doesn't allow filenames to end with a dot or a space
+    name.trim_start().trim_end_matches(['.', ' ']).to_string()
+}
+
+#[derive(Debug)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Rename {
+    pub fn is_noop(&self) -> bool {
+        self.from == self.to
+    }
+
+    /// Renames the file.
+    pub fn apply(&self) -> anyhow::Result<()> {
+        fs::rename(&self.from, &self.to)
+            .map_err(|e| Error::io(&self.from, e).into())
+    }
+}
+
+/// Builds new names for the files from their tags, using the pattern as a
+/// template.
+pub fn plan_renames(
+    files: &[AudioFile],
+    pattern: &ParsePattern,
+) -> Vec<anyhow::Result<Rename>> {
+    let metadata: Vec<_> = files.iter().map(AudioFile::read_metadata).collect();
+    let track_width = track_width(&metadata);
+
+    let mut renames: Vec<_> = files
+        .iter()
+        .zip(metadata)
+        .map(|(file, metadata)| {
+            let name =
+                pattern.render(&metadata?, track_width).context(format!(
+                    "Can't build a new name for the file '{}'",
+                    file.path()
+                ))?;
+            let name = sanitize_filename(&name);
+
+            if name.is_empty() {
+                bail!("New name for the file '{}' is empty", file.path());
+            }
+
+            let from = file.as_path().to_path_buf();
+            let name = match from.extension() {
+                Some(ext) => format!("{name}.{}", ext.to_string_lossy()),
+                None => name,
+            };
+            let to = from.with_file_name(name);
+
+            Ok(Rename { from, to })
+        })
+        .collect();
+
+    check_collisions(&mut renames);
+
+    renames
+}
+
+/// Width of track numbers, that are rendered in the new names. Track numbers
+/// are padded to the same width, so that the files are sorted correctly by
+/// name.
+pub(crate) fn track_width(metadata: &[anyhow::Result<Metadata>]) -> usize {
+    let max_track = metadata
+        .iter()
+        .filter_map(|x| x.as_ref().ok()?.track_number.as_ref()?.number())
+        .max()
+        .unwrap_or_default();
+
+    max_track.to_string().len().max(2)
+}
+
+/// Turns renames, that would overwrite existing files or each other, into
+/// errors.
+pub fn check_collisions(renames: &mut [anyhow::Result<Rename>]) {
+    let mut targets: HashMap<PathBuf, usize> = HashMap::new();
+
+    for rename in renames.iter().flatten() {
+        *targets.entry(rename.to.clone()).or_default() += 1;
+    }
+
+    for rename in renames.iter_mut() {
+        let error = match rename {
+            Ok(x) if x.is_noop() => None,
+            Ok(x) if targets[&x.to] > 1 => Some(anyhow!(
+                "Several files would be moved to '{}'",
+                x.to.to_string_lossy()
+            )),
+            Ok(x) if x.to.exists() => Some(anyhow!(
+                "Can't move '{}', because the file '{}' already exists",
+                x.from.to_string_lossy(),
+                x.to.to_string_lossy()
+            )),
+            _ => None,
+        };
+
+        if let Some(e) = error {
+            *rename = Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(sanitize_filename("AC/DC - T.N.T."), "AC_DC - T.N.T");
+        assert_eq!(sanitize_filename("What? <Live>"), "What_ _Live_");
+        assert_eq!(sanitize_filename(" Foo: Bar "), "Foo_ Bar");
+    }
+}
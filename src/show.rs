@@ -0,0 +1,320 @@
+use std::fmt;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::metadata::{AudioFile, Field, Frame, Metadata};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+pub enum OutputFormat {
+    /// Human-readable table
+    #[default]
+    Table,
+
+    Json,
+
+    Toml,
+}
+
+/// Metadata, that is already written to the file, as it is shown to the
+/// user.
+#[derive(Debug)]
+pub struct TagSummary {
+    path: String,
+    format: String,
+    metadata: Metadata,
+    cover: bool,
+    frames: Vec<Frame>,
+}
+
+impl TagSummary {
+    pub fn new(file: &AudioFile) -> anyhow::Result<Self> {
+        let raw_tag = file.read_raw_tag()?;
+
+        Ok(Self {
+            path: file.path(),
+            format: raw_tag.format,
+            metadata: file.read_metadata()?,
+            cover: raw_tag.has_cover,
+            frames: raw_tag.frames,
+        })
+    }
+}
+
+// Fields are serialized in the order of `Field`, numbers as numbers and
+// the fields without a value are skipped
+impl Serialize for TagSummary {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("path", &self.path)?;
+        map.serialize_entry("format", &self.format)?;
+
+        for field in shown_fields() {
+            let Some(value) = self.metadata.get(field) else {
+                continue;
+            };
+            let key = field.name().replace('-', "_");
+
+            match value.parse::<u32>() {
+                Ok(number) if !field.is_text() => {
+                    map.serialize_entry(&key, &number)?
+                }
+                _ => map.serialize_entry(&key, &value)?,
+            }
+        }
+
+        map.serialize_entry("cover", &self.cover)?;
+        map.serialize_entry("frames", &self.frames)?;
+        map.end()
+    }
+}
+
+// Metadata is shown as a human-readable table
+impl fmt::Display for TagSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.path, self.format)?;
+
+        for (name, value) in field_rows(&self.metadata) {
+            write!(f, "\n  {name:<14}{}", value.as_deref().unwrap_or("-"))?;
+        }
+
+        let cover = if self.cover { "yes" } else { "no" };
+        write!(f, "\n  {:<14}{cover}", "Cover")?;
+
+        if !self.frames.is_empty() {
+            let width = self.frames.iter().map(|x| x.id.len()).max();
+            let width = width.unwrap_or_default();
+
+            write!(f, "\n  Frames:")?;
+
+            for frame in &self.frames {
+                write!(f, "\n    {:<width$}  {}", frame.id, frame.value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// TOML document can't consist of an array only, so we need to put it in a
+// table
+#[derive(Serialize)]
+struct TomlDocument<'a> {
+    file: &'a [TagSummary],
+}
+
+/// Formats metadata of the files in the given format.
+pub fn format_tags(
+    summaries: &[TagSummary],
+    format: OutputFormat,
+) -> anyhow::Result<String> {
+    match format {
+        OutputFormat::Table => {
+            let tables: Vec<_> =
+                summaries.iter().map(ToString::to_string).collect();
+
+            Ok(tables.join("\n\n"))
+        }
+
+        OutputFormat::Json => serde_json::to_string_pretty(summaries)
+            .context("Failed to serialize metadata to JSON"),
+
+        OutputFormat::Toml => {
+            toml::to_string(&TomlDocument { file: summaries })
+                .context("Failed to serialize metadata to TOML")
+        }
+    }
+}
+
+/// What writing the new metadata would do with a field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The field is left as it is
+    Unchanged,
+
+    /// The given value is written to the field
+    Set(String),
+
+    /// The field is removed from the file
+    Removed,
+}
+
+/// Value of a field, that is written to the file, and its change.
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub name: String,
+    pub old: Option<String>,
+    pub change: Change,
+}
+
+/// Returns the changes of the fields between the metadata of the file
+/// before and after writing. Covers are given as they are shown to the user.
+pub fn diff(
+    old: &Metadata,
+    old_cover: Option<String>,
+    new: &Metadata,
+    new_cover: Option<String>,
+) -> Vec<FieldChange> {
+    let mut rows: Vec<_> = field_rows(old)
+        .into_iter()
+        .zip(field_rows(new))
+        .map(|((name, old), (_, new))| (name.to_string(), old, new))
+        .collect();
+
+    rows.push(("Cover".to_string(), old_cover, new_cover));
+
+    for field in &new.custom_fields {
+        let old = old.custom_fields.iter().find(|x| x.key == field.key);
+        let old = old.map(|x| x.value.clone());
+
+        rows.push((field.key.clone(), old, Some(field.value.clone())));
+    }
+
+    rows.into_iter()
+        .map(|(name, old, new)| {
+            let change = match new {
+                Some(new) if Some(&new) != old.as_ref() => Change::Set(new),
+                None if old.is_some() => Change::Removed,
+                _ => Change::Unchanged,
+            };
+
+            FieldChange { name, old, change }
+        })
+        .collect()
+}
+
+/// Fields, that are shown in the table. Cover is shown separately, because
+/// only its presence is known for the existing tags.
+fn shown_fields() -> impl Iterator<Item = Field> {
+    Field::value_variants()
+        .iter()
+        .copied()
+        .filter(|x| *x != Field::Cover)
+}
+
+/// Values of the fields (except for the cover), as they are shown in the
+/// table and in the difference.
+fn field_rows(metadata: &Metadata) -> Vec<(&'static str, Option<String>)> {
+    shown_fields()
+        .map(|field| {
+            let value = metadata.get(field);
+            let value = match field {
+                Field::Lyrics => value.as_deref().map(first_line),
+                _ => value,
+            };
+
+            (field.label(), value)
+        })
+        .collect()
+}
+
+/// Lyrics are too long to be shown in the table, so only the first line is
+/// shown.
+fn first_line(text: &str) -> String {
+    let mut lines = text.lines();
+    let first = lines.next().unwrap_or_default();
+
+    match lines.next() {
+        Some(_) => format!("{first} ..."),
+        None => first.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{CustomField, NumberOrToken};
+
+    fn custom(key: &str, value: &str) -> CustomField {
+        CustomField {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn change<'a>(changes: &'a [FieldChange], name: &str) -> &'a Change {
+        &changes.iter().find(|x| x.name == name).unwrap().change
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Metadata {
+            title: Some("Bar".to_string()),
+            artist: Some("Foo".to_string()),
+            comment: Some("Old".to_string()),
+            custom_fields: vec![custom("label", "Baz")],
+            ..Default::default()
+        };
+        let new = Metadata {
+            title: Some("Quux".to_string()),
+            artist: Some("Foo".to_string()),
+            custom_fields: vec![custom("label", "Baz"), custom("isrc", "X")],
+            ..Default::default()
+        };
+        let embedded = Some("<embedded image>".to_string());
+        let changes = diff(&old, embedded, &new, Some("cover.jpg".to_string()));
+
+        assert_eq!(change(&changes, "Title"), &Change::Set("Quux".to_string()));
+        assert_eq!(change(&changes, "Artist"), &Change::Unchanged);
+        assert_eq!(change(&changes, "Comment"), &Change::Removed);
+        assert_eq!(change(&changes, "Year"), &Change::Unchanged);
+        assert_eq!(
+            change(&changes, "Cover"),
+            &Change::Set("cover.jpg".to_string())
+        );
+        assert_eq!(change(&changes, "label"), &Change::Unchanged);
+        assert_eq!(change(&changes, "isrc"), &Change::Set("X".to_string()));
+    }
+
+    #[test]
+    fn test_format_tags() {
+        let summaries = [TagSummary {
+            path: "Foo - Bar.mp3".to_string(),
+            format: "ID3v2.4".to_string(),
+            metadata: Metadata {
+                title: Some("Bar".to_string()),
+                artist: Some("Foo".to_string()),
+                year: Some(NumberOrToken::Number(2001)),
+                ..Default::default()
+            },
+            cover: false,
+            frames: Vec::new(),
+        }];
+
+        let table = format_tags(&summaries, OutputFormat::Table).unwrap();
+        assert!(
+            table.starts_with("Foo - Bar.mp3 (ID3v2.4)\n  Title         Bar")
+        );
+        assert!(table.contains("\n  Year          2001\n"));
+        assert!(table.contains("\n  Album         -\n"));
+
+        // Fields without a value are skipped and numbers stay numbers
+        let json = format_tags(&summaries, OutputFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "path": "Foo - Bar.mp3",
+                "format": "ID3v2.4",
+                "title": "Bar",
+                "artist": "Foo",
+                "year": 2001,
+                "cover": false,
+                "frames": [],
+            }])
+        );
+
+        let toml = format_tags(&summaries, OutputFormat::Toml).unwrap();
+        assert_eq!(
+            toml,
+            "[[file]]\npath = \"Foo - Bar.mp3\"\nformat = \"ID3v2.4\"\n\
+            title = \"Bar\"\nartist = \"Foo\"\nyear = 2001\ncover = false\n\
+            frames = []\n"
+        );
+    }
+}
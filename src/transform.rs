@@ -0,0 +1,218 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::error::Error;
+
+/// Modification of the text, that is applied to the value of a token, like
+/// `{t|title}` or `${1|trim|lower}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// Remove whitespace at the start and at the end
+    Trim,
+    /// Replace runs of whitespace with a single space
+    Collapse,
+    Lower,
+    Upper,
+    /// Capitalize the first letter of every word and lowercase the rest
+    Title,
+    /// Replace all occurrences of the first text with the second one
+    Replace(String, String),
+}
+
+impl Transform {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Transform::Trim => value.trim().to_string(),
+            Transform::Collapse => {
+                value.split_whitespace().collect::<Vec<_>>().join(" ")
+            }
+            Transform::Lower => value.to_lowercase(),
+            Transform::Upper => value.to_uppercase(),
+            Transform::Title => title_case(value),
+            Transform::Replace(from, to) => value.replace(from, to),
+        }
+    }
+}
+
+impl FromStr for Transform {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        let transform = match string {
+            "trim" => Transform::Trim,
+            "collapse" => Transform::Collapse,
+            "lower" => Transform::Lower,
+            "upper" => Transform::Upper,
+            "title" => Transform::Title,
+            _ => {
+                // Arguments are taken literally, including spaces, so that
+                // e.g. 'replace(_, )' replaces underscores with spaces
+                let args = string
+                    .strip_prefix("replace(")
+                    .and_then(|x| x.strip_suffix(')'))
+                    .context(format!("Unknown transform '{string}'"))?;
+                let (from, to) = args.split_once(',').context(format!(
+                    "Transform '{string}' should have two arguments"
+                ))?;
+
+                if from.is_empty() {
+                    bail!("Can't replace an empty text in '{string}'");
+                }
+
+                Transform::Replace(from.to_string(), to.to_string())
+            }
+        };
+
+        Ok(transform)
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::Trim => write!(f, "trim"),
+            Transform::Collapse => write!(f, "collapse"),
+            Transform::Lower => write!(f, "lower"),
+            Transform::Upper => write!(f, "upper"),
+            Transform::Title => write!(f, "title"),
+            Transform::Replace(from, to) => write!(f, "replace({from},{to})"),
+        }
+    }
+}
+
+/// Splits the contents of a token, like `t|trim|replace(|,-)`, into the name
+/// and the transforms. '|' inside of the arguments doesn't separate
+/// transforms.
+pub fn split_transforms(
+    string: &str,
+) -> anyhow::Result<(&str, Vec<Transform>)> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in string.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '|' if depth == 0 => {
+                parts.push(&string[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&string[start..]);
+
+    let transforms = parts[1..]
+        .iter()
+        .map(|x| x.parse())
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok((parts[0], transforms))
+}
+
+pub fn apply_transforms(transforms: &[Transform], value: &str) -> String {
+    transforms
+        .iter()
+        .fold(value.to_string(), |acc, x| x.apply(&acc))
+}
+
+/// Puts values in place of the tokens like `${1}` or `${name|trim}` in the
+/// template. Tokens, that have no value, are errors, so that they are never
+/// written as they are.
+pub fn expand_template<'a>(
+    template: &str,
+    value_of: impl Fn(&str) -> Option<&'a str>,
+) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+
+        let (name, transforms) = split_transforms(&rest[2..end])?;
+
+        match value_of(name) {
+            Some(value) => {
+                output.push_str(&apply_transforms(&transforms, value));
+            }
+            None => {
+                return Err(Error::Parse(format!(
+                    "There is no group '{name}' for the token '{}'",
+                    &rest[..=end]
+                ))
+                .into())
+            }
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+fn title_case(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    let mut is_word_start = true;
+
+    for c in value.chars() {
+        if is_word_start {
+            output.extend(c.to_uppercase());
+        } else {
+            output.extend(c.to_lowercase());
+        }
+
+        // Apostrophes don't start a new word, e.g. in "don't"
+        is_word_start = !c.is_alphanumeric() && c != '\'';
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transforms() {
+        let (name, transforms) =
+            split_transforms("t|replace(_, )|collapse|title").unwrap();
+
+        assert_eq!(name, "t");
+        assert_eq!(
+            apply_transforms(&transforms, "DON'T_STOP__ME_NOW"),
+            "Don't Stop Me Now"
+        );
+
+        let (_, transforms) = split_transforms("a|replace(|,&)").unwrap();
+        assert_eq!(apply_transforms(&transforms, "Foo|Bar"), "Foo&Bar");
+
+        assert!(split_transforms("a|reverse").is_err());
+        assert!(split_transforms("a|replace(x)").is_err());
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let value_of = |name: &str| match name {
+            "1" => Some(" foo "),
+            "artist" => Some("BAR"),
+            _ => None,
+        };
+
+        assert_eq!(
+            expand_template("${1|trim} - ${artist|lower}", value_of).unwrap(),
+            "foo - bar"
+        );
+        assert!(expand_template("${1} ${2}", value_of).is_err());
+    }
+}